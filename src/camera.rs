@@ -0,0 +1,133 @@
+use std::io;
+use std::path::PathBuf;
+
+use hyper::Client;
+use hyper::status::StatusCode;
+
+use config::Config;
+use error::{Error,Result};
+use gpx::read_log;
+use transfer::*;
+
+/// Identity and connection state as reported by the camera.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraInfo {
+  pub model: String,
+  pub connect_mode: String,
+}
+
+impl CameraInfo {
+  pub fn fetch(client: &Client) -> Result<Self> {
+    let caminfo = try!(request(client, "get_caminfo.cgi"));
+    let connectmode = try!(request(client, "get_connectmode.cgi"));
+
+    let model = try!(xml_element(&caminfo, "model").ok_or(Error::ProtocolError));
+    let connect_mode = try!(xml_element(&connectmode, "connectmode").ok_or(Error::ProtocolError));
+
+    Ok(CameraInfo {
+      model: model,
+      connect_mode: connect_mode,
+    })
+  }
+}
+
+/// Extracts the text of the first `<name>` element. The camera only
+/// answers with flat documents, so this is all the XML we need.
+pub fn xml_element(body: &str, name: &str) -> Option<String> {
+  let open = format!("<{}>", name);
+  let close = format!("</{}>", name);
+  let start = match body.find(&open) {
+    Some(i) => i + open.len(),
+    None => return None,
+  };
+  body[start..].find(&close).map(|end| body[start..start + end].trim().to_string())
+}
+
+#[test]
+fn test_xml_element() {
+  let caminfo = "<?xml version=\"1.0\"?>\r\n<caminfo><model>E-M10MarkII</model></caminfo>";
+  assert_eq!(xml_element(caminfo, "model"), Some("E-M10MarkII".to_string()));
+
+  let connectmode = "<?xml version=\"1.0\"?>\r\n<connectmode>private</connectmode>\r\n";
+  assert_eq!(xml_element(connectmode, "connectmode"), Some("private".to_string()));
+  assert_eq!(xml_element(connectmode, "model"), None);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraStatus {
+  pub info: CameraInfo,
+  pub images: usize,
+  pub transfer_order: usize,
+  pub pending_files: usize,
+  pub pending_bytes: u64,
+}
+
+/// Pending files are the ones the transfers configured in `config`
+/// would download.
+pub fn camera_status(config: &Config) -> Result<CameraStatus> {
+  let client = Client::new();
+
+  let info = try!(CameraInfo::fetch(&client));
+  let images = try!(list_items(&client));
+  let order = try!(request_list(&client, "get_rsvimglist.cgi"));
+
+  let mut pending: Vec<TransferItem> = Vec::new();
+
+  if let Some(transfer) = OrderTransfer::from_config(config) {
+    pending.extend(try!(transfer.pending(order.clone())));
+  }
+
+  if let Some(transfer) = IncrementalTransfer::from_config(config) {
+    let last_downloaded = try!(transfer.read_state());
+    for item in images.iter() {
+      if transfer.is_new(item, last_downloaded) && !pending.contains(item) {
+        pending.push(item.clone());
+      }
+    }
+  }
+
+  Ok(CameraStatus {
+    info: info,
+    images: images.len(),
+    transfer_order: order.len(),
+    pending_files: pending.len(),
+    pending_bytes: pending.iter().map(|item| item.file_size).sum(),
+  })
+}
+
+/// Sends a GPS log in NMEA format to the camera, which writes the
+/// positions into the images on its card taken during the log.
+pub fn upload_gps_log(client: &Client, nmea: &str) -> Result<()> {
+  if !nmea.lines().any(|line| line.starts_with('$')) {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "No positions to upload").into());
+  }
+
+  let url = format!("{}req_attachexifgps.cgi", BASE_URL);
+  debug!("Posting {} bytes to {}", nmea.len(), url);
+
+  let res = try!(client.post(&url).body(nmea).send());
+  if res.status != StatusCode::Ok {
+    return Err(Error::ProtocolError)
+  }
+  Ok(())
+}
+
+/// Uploads the GPX or NMEA logs at `paths` in one go, see `read_log`.
+/// Returns the number of NMEA sentences sent.
+pub fn upload_gps_logs(paths: &[PathBuf]) -> Result<usize> {
+  let mut nmea = String::new();
+  for path in paths {
+    nmea.push_str(&try!(read_log(path)));
+    if !nmea.ends_with('\n') {
+      nmea.push_str("\r\n");
+    }
+  }
+
+  try!(upload_gps_log(&Client::new(), &nmea));
+  Ok(nmea.lines().filter(|line| line.starts_with('$')).count())
+}
+
+#[test]
+fn test_upload_empty_gps_log() {
+  assert!(upload_gps_log(&Client::new(), "").is_err());
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::path::Path;
+use std::result;
+
+use hyper::Client;
+
+use config::{Config,ConfigLayers};
+use transfer::*;
+
+/// A single entry of the checklist printed by `config check`.
+pub struct Check {
+  pub description: String,
+  pub outcome: result::Result<(), String>,
+}
+
+impl Check {
+  fn new<S: Into<String>>(description: S, outcome: result::Result<(), String>) -> Self {
+    Check {
+      description: description.into(),
+      outcome: outcome,
+    }
+  }
+
+  pub fn passed(&self) -> bool {
+    self.outcome.is_ok()
+  }
+}
+
+/// Validates everything that would otherwise only fail in the middle of
+/// a transfer, without contacting the camera.
+pub fn check_config(layers: &ConfigLayers) -> Vec<Check> {
+  let mut checks = Vec::new();
+
+  let config = match layers.build() {
+    Ok(config) => {
+      checks.push(Check::new("Config is valid", Ok(())));
+      config
+    },
+    Err(e) => {
+      checks.push(Check::new("Config is valid", Err(e.to_string())));
+      return checks;
+    }
+  };
+
+  if config.download_dir.is_none() && config.transfer_order_dir.is_none() {
+    checks.push(Check::new("At least one transfer is configured",
+                           Err("Neither `incremental` nor `transfer_order` is set".into())));
+  }
+
+  if let Some(ref dir) = config.transfer_order_dir {
+    checks.push(Check::new(format!("Transfer order directory {} is writable", dir.display()),
+                           check_writable(dir)));
+  }
+
+  if let Some(ref dir) = config.download_dir {
+    checks.push(Check::new(format!("Incremental directory {} is writable", dir.display()),
+                           check_writable(dir)));
+  }
+
+  if let Some(transfer) = OrderTransfer::from_config(&config) {
+    let outcome = transfer.pending(Vec::new())
+      .map(|_| ())
+      .map_err(|e| e.to_string());
+    checks.push(Check::new(format!("Transfer order record {} is readable",
+                                   transfer.record_file().display()),
+                           outcome));
+  }
+
+  if let Some(transfer) = IncrementalTransfer::from_config(&config) {
+    let outcome = transfer.read_state()
+      .map(|_| ())
+      .map_err(|e| e.to_string());
+    checks.push(Check::new(format!("State file {} is readable", transfer.state_file().display()),
+                           outcome));
+
+    if let Some(dir) = transfer.state_file().parent() {
+      checks.push(Check::new(format!("State directory {} is writable", dir.display()),
+                             check_writable(dir)));
+    }
+  }
+
+  if let Some(ref wifi) = config.wifi {
+    checks.push(Check::new(format!("Network {} is configured on {}", wifi.ssid, wifi.interface),
+                           check_wifi(&config)));
+  }
+
+  checks
+}
+
+/// Requests a listing from the camera, so its network has to be up.
+pub fn check_camera() -> Check {
+  let outcome = ping_camera(&Client::new()).map_err(|e| e.to_string());
+  Check::new("Camera responds to listing requests", outcome)
+}
+
+/// Checks that files can be created in `dir`. If `dir` doesn't exist yet
+/// its nearest existing ancestor is checked instead, as the directory
+/// gets created on the first transfer.
+fn check_writable(dir: &Path) -> result::Result<(), String> {
+  let mut existing = dir;
+  while !existing.exists() {
+    existing = match existing.parent() {
+      Some(p) => p,
+      None => return Err(format!("No existing parent of {}", dir.display())),
+    };
+  }
+
+  if !existing.is_dir() {
+    return Err(format!("{} is not a directory", existing.display()));
+  }
+
+  let probe = existing.join(".omd-transfer-check");
+  try!(fs::File::create(&probe)
+       .map_err(|e| format!("Can't write to {}: {}", existing.display(), e)));
+  try!(fs::remove_file(&probe)
+       .map_err(|e| format!("Can't remove {}: {}", probe.display(), e)));
+  Ok(())
+}
+
+#[cfg(feature = "dbus")]
+fn check_wifi(config: &Config) -> result::Result<(), String> {
+  use wifi;
+  match config.wifi {
+    Some(ref wifi_config) => wifi::check_network(wifi_config),
+    None => Ok(()),
+  }
+}
+
+#[cfg(not(feature = "dbus"))]
+fn check_wifi(_config: &Config) -> result::Result<(), String> {
+  Err("Found `wifi` section in config but compiled without DBUS support".into())
+}
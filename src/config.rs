@@ -1,9 +1,16 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
 use std::path::{Path,PathBuf};
 use std::io::{Read};
 use std::fs::File;
 
+use chrono::{Duration,FixedOffset};
 use toml;
 
+use error::{Error,Result};
+use naming::NameTemplate;
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum ErrorStrategy {
   Abort,
@@ -36,88 +43,849 @@ impl OverwriteStrategy {
   }
 }
 
+/// How RAW (`.ORF`) and JPEG files of the same shot are handled.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum PairStrategy {
+  /// Files are transferred independently
+  None,
+  /// If one file of a pair is transferred, the other one is too
+  Complete,
+  /// Like `Complete`, but JPEGs are only transferred without a RAW
+  PreferRaw,
+}
+
+impl PairStrategy {
+  fn from_str(v: &str) -> Option<Self> {
+    match v {
+      "none"       => Some(PairStrategy::None),
+      "complete"   => Some(PairStrategy::Complete),
+      "prefer_raw" => Some(PairStrategy::PreferRaw),
+      _            => None
+    }
+  }
+}
+
+/// What happens to a downloaded file whose contents were archived
+/// before.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DuplicateStrategy {
+  /// Store it again
+  Keep,
+  /// Don't store it, the history points to the existing file
+  Skip,
+  /// Replace it with a hard link to the existing file
+  Hardlink,
+  /// Replace it with a copy-on-write clone of the existing file
+  Reflink,
+}
+
+impl DuplicateStrategy {
+  fn from_str(v: &str) -> Option<Self> {
+    match v {
+      "keep"     => Some(DuplicateStrategy::Keep),
+      "skip"     => Some(DuplicateStrategy::Skip),
+      "hardlink" => Some(DuplicateStrategy::Hardlink),
+      "reflink"  => Some(DuplicateStrategy::Reflink),
+      _          => None
+    }
+  }
+}
+
+/// Which timestamps of downloaded files are set to the capture time.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum FileTimes {
+  /// Leave them at the time of the download
+  Download,
+  /// Set the modification time
+  Modified,
+  /// Set the modification and access time
+  All,
+}
+
+impl FileTimes {
+  fn from_str(v: &str) -> Option<Self> {
+    match v {
+      "download" => Some(FileTimes::Download),
+      "modified" => Some(FileTimes::Modified),
+      "all"      => Some(FileTimes::All),
+      _          => None
+    }
+  }
+}
+
+/// Timezone the camera clock is set to. The camera doesn't store it.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CameraTimezone {
+  /// The timezone of this computer
+  Local,
+  /// A fixed offset from UTC, e.g. `+02:00`
+  Fixed(FixedOffset),
+  /// Derived from the camera clock, see `timezone::detect_offset`
+  Auto,
+}
+
+impl CameraTimezone {
+  fn from_str(v: &str) -> Option<Self> {
+    match v {
+      "local" => Some(CameraTimezone::Local),
+      "auto"  => Some(CameraTimezone::Auto),
+      "UTC" | "Z" => Some(CameraTimezone::Fixed(FixedOffset::east(0))),
+      _ => parse_offset(v).map(CameraTimezone::Fixed),
+    }
+  }
+}
+
+/// Parses offsets like `+02:00` or `-0530`.
+fn parse_offset(v: &str) -> Option<FixedOffset> {
+  let sign = match v.chars().next() {
+    Some('+') => 1,
+    Some('-') => -1,
+    _ => return None,
+  };
+
+  let digits: String = v[1..].chars().filter(|&c| c != ':').collect();
+  if digits.len() != 4 || !digits.chars().all(|c| c.is_digit(10)) {
+    return None;
+  }
+
+  let hours: i32 = digits[..2].parse().unwrap();
+  let minutes: i32 = digits[2..].parse().unwrap();
+  if hours > 14 || minutes >= 60 {
+    return None;
+  }
+  FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
 #[derive(Clone, Debug)]
 pub struct WifiConfig {
   pub interface: String,
   pub ssid: String,
 }
 
+/// Geotagging of downloaded files from GPX tracks.
+#[derive(Clone, Debug)]
+pub struct GeotagConfig {
+  /// Directory containing the `.gpx` files
+  pub gpx_dir: PathBuf,
+  /// Added to capture times to get the time of the GPS logger, e.g.
+  /// -30 seconds if the camera clock is 30 seconds ahead
+  pub time_offset: Duration,
+  /// Positions aren't interpolated between points further apart
+  pub max_gap: Duration,
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
+  /// The selected `[camera.<name>]` profile, if any
+  pub camera: Option<String>,
+
   pub download_dir: Option<PathBuf>,
   pub transfer_order_dir: Option<PathBuf>,
+  /// Target of items picked interactively. Defaults to
+  /// `transfer_order_dir`.
+  pub selection_dir: Option<PathBuf>,
+
+  /// Where the incremental transfer remembers the last download.
+  /// Defaults to `$XDG_STATE_HOME/omd-transfer/<camera>.state`.
+  pub incremental_state_file: PathBuf,
+  /// Where the transfer order transfer records delivered items.
+  /// Defaults to `$XDG_STATE_HOME/omd-transfer/<camera>.delivered`.
+  pub transfer_order_state_file: PathBuf,
+
+  /// Download JPEGs resized to this width instead of originals
+  pub incremental_resize: Option<u32>,
+  pub transfer_order_resize: Option<u32>,
 
   pub error_strategy: ErrorStrategy,
   pub overwrite_strategy: OverwriteStrategy,
+  pub pair_strategy: PairStrategy,
+  pub duplicate_strategy: DuplicateStrategy,
+
+  /// Names downloaded files instead of the camera, see `NameTemplate`
+  pub rename_template: Option<NameTemplate>,
+
+  pub camera_timezone: CameraTimezone,
+  /// Set the camera clock before the transfers, see `sync_clock`
+  pub sync_clock: bool,
+  pub file_times: FileTimes,
+
+  pub geotag: Option<GeotagConfig>,
+
+  /// Write XMP sidecars with import metadata for every download
+  pub xmp_sidecars: bool,
+  /// Keywords added to the sidecars
+  pub xmp_keywords: Vec<String>,
 
-  pub wifi: Option<WifiConfig>
+  pub wifi: Option<WifiConfig>,
+
+  /// Where thumbnails and screennails are stored
+  pub preview_cache_dir: PathBuf,
+
+  /// SQLite database recording every transferred file
+  pub history_file: PathBuf,
 }
 
-impl Config {
-  pub fn from_file<P: AsRef<Path>>(file: P) -> Self {
-    info!("Loading config from {}", file.as_ref().display());
+/// Built-in defaults, the lowest configuration layer.
+const DEFAULTS: &'static str = r#"
+error_strategy = "abort"
+overwrite_strategy = "skip"
+pair_strategy = "none"
+duplicate_strategy = "keep"
+camera_timezone = "local"
+sync_clock = false
+file_times = "modified"
+"#;
+
+/// Keys which can be set via `OMD_TRANSFER_*` environment variables.
+const ENV_KEYS: &'static [&'static str] = &[
+  "error_strategy",
+  "overwrite_strategy",
+  "pair_strategy",
+  "duplicate_strategy",
+  "rename_template",
+  "camera_timezone",
+  "sync_clock",
+  "file_times",
+  "transfer_order.download_directory",
+  "transfer_order.state_file",
+  "incremental.download_directory",
+  "incremental.state_file",
+  "selection.download_directory",
+  "wifi.interface",
+  "wifi.ssid",
+  "preview.cache_directory",
+  "geotag.gpx_directory",
+  "geotag.time_offset",
+  "geotag.max_gap",
+  "xmp.sidecars",
+  "xmp.keywords",
+  "history.database",
+  "transfer_order.resize",
+  "incremental.resize",
+];
+
+/// Image widths supported by `get_resizeimg.cgi`.
+pub const RESIZE_SIZES: &'static [u32] = &[1024, 1600, 1920, 2048];
+
+pub const SYSTEM_CONFIG: &'static str = "/etc/omd-transfer.toml";
+
+/// The per-user config file, `~/.omd-transfer.toml`.
+pub fn user_config_path() -> PathBuf {
+  let mut homedir = env::home_dir().expect("Couldn't get home dir");
+  homedir.push(".omd-transfer.toml");
+  homedir
+}
+
+/// Where a configuration value came from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigSource {
+  Default,
+  File(PathBuf),
+  Env(String),
+  Cli,
+}
+
+impl fmt::Display for ConfigSource {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ConfigSource::Default => write!(f, "default"),
+      ConfigSource::File(ref p) => write!(f, "{}", p.display()),
+      ConfigSource::Env(ref var) => write!(f, "environment ({})", var),
+      ConfigSource::Cli => write!(f, "command line"),
+    }
+  }
+}
+
+/// The merged configuration layers. Values are stored by their dotted
+/// key (e.g. `incremental.download_directory`); later layers replace
+/// values of earlier ones.
+#[derive(Clone, Debug)]
+pub struct ConfigLayers {
+  values: BTreeMap<String, (toml::Value, ConfigSource)>,
+  files: Vec<PathBuf>,
+  camera: Option<String>,
+}
+
+impl ConfigLayers {
+  pub fn new() -> Self {
+    let mut layers = ConfigLayers {
+      values: BTreeMap::new(),
+      files: Vec::new(),
+      camera: None,
+    };
+    let defaults: toml::Value = DEFAULTS.parse().unwrap();
+    layers.merge("", &defaults, &ConfigSource::Default);
+    layers
+  }
+
+  /// Stacks defaults, the system config, the user config (or `file`,
+  /// if given), the environment and `overrides` of the form `key=value`.
+  pub fn load(file: Option<&Path>, overrides: &[String]) -> Result<Self> {
+    let mut layers = ConfigLayers::new();
+
+    let system = Path::new(SYSTEM_CONFIG);
+    if system.exists() {
+      try!(layers.add_file(system));
+    }
+
+    match file {
+      Some(file) if !file.exists() => {
+        return Err(Error::Config(format!("File {} not found", file.display())))
+      },
+      Some(file) => try!(layers.add_file(file)),
+      None => {
+        let user = user_config_path();
+        if user.exists() {
+          try!(layers.add_file(&user));
+        }
+      }
+    }
+
+    layers.add_env();
+
+    for assignment in overrides {
+      try!(layers.add_override(assignment));
+    }
+
+    Ok(layers)
+  }
+
+  pub fn add_file<P: AsRef<Path>>(&mut self, file: P) -> Result<()> {
+    let file = file.as_ref();
+    info!("Loading config from {}", file.display());
 
     let conf: toml::Value = {
       let mut buf = String::new();
-      let mut file = File::open(file).expect("Failed to parse config!");
-      file.read_to_string(&mut buf).expect("Failed to parse config!");
+      let mut f = try!(File::open(file));
+      try!(f.read_to_string(&mut buf));
+
+      try!(buf.parse().map_err(|errors: Vec<toml::ParserError>| {
+        let reason = errors.first().map(|e| e.to_string()).unwrap_or_default();
+        Error::Config(format!("Failed to parse {}: {}", file.display(), reason))
+      }))
+    };
 
-      buf.parse().expect("Failed to parse config!")
+    self.merge("", &conf, &ConfigSource::File(file.to_path_buf()));
+    self.files.push(file.to_path_buf());
+    Ok(())
+  }
+
+  pub fn add_env(&mut self) {
+    for key in ENV_KEYS {
+      let var = env_var_name(key);
+      if let Ok(value) = env::var(&var) {
+        self.set(key, toml::Value::String(value), ConfigSource::Env(var));
+      }
+    }
+  }
+
+  /// Applies a `key=value` assignment given on the command line. The
+  /// value is parsed as TOML and falls back to a plain string.
+  pub fn add_override(&mut self, assignment: &str) -> Result<()> {
+    let mut parts = assignment.splitn(2, '=');
+    let key = parts.next().unwrap().trim();
+    let raw = match parts.next() {
+      Some(raw) if !key.is_empty() => raw.trim(),
+      _ => return Err(Error::Config(format!("Expected key=value, got `{}`", assignment))),
     };
 
-    let error_strategy = conf.lookup("error_strategy")
-      .expect("`error_strategy` not found in config file")
-      .as_str()
+    let value = format!("value = {}", raw).parse::<toml::Value>().ok()
+      .and_then(|v| v.lookup("value").cloned())
+      .unwrap_or(toml::Value::String(raw.to_string()));
+
+    self.set(key, value, ConfigSource::Cli);
+    Ok(())
+  }
+
+  pub fn set(&mut self, key: &str, value: toml::Value, source: ConfigSource) {
+    // A table replaces all values below its key
+    let prefix = format!("{}.", key);
+    let stale: Vec<String> = self.values.keys()
+      .filter(|k| k.starts_with(&prefix))
+      .cloned()
+      .collect();
+    for k in stale {
+      self.values.remove(&k);
+    }
+
+    match value {
+      toml::Value::Table(_) => self.merge(key, &value, &source),
+      value => { self.values.insert(key.to_string(), (value, source)); },
+    }
+  }
+
+  fn merge(&mut self, prefix: &str, value: &toml::Value, source: &ConfigSource) {
+    match *value {
+      toml::Value::Table(ref table) => {
+        for (k, v) in table {
+          let key = if prefix.is_empty() {
+            k.clone()
+          } else {
+            format!("{}.{}", prefix, k)
+          };
+          self.merge(&key, v, source);
+        }
+      },
+      ref value => {
+        self.values.insert(prefix.to_string(), (value.clone(), source.clone()));
+      }
+    }
+  }
+
+  pub fn get(&self, key: &str) -> Option<&toml::Value> {
+    self.values.get(key).map(|&(ref v, _)| v)
+  }
+
+  fn get_str(&self, key: &str) -> Option<&str> {
+    self.get(key).and_then(toml::Value::as_str)
+  }
+
+  /// Reads a resize width, which may be given as integer or string (when
+  /// set via the environment).
+  fn get_resize(&self, key: &str) -> Result<Option<u32>> {
+    let size = match self.get(key) {
+      None => return Ok(None),
+      Some(&toml::Value::Integer(size)) => Some(size),
+      Some(&toml::Value::String(ref size)) => size.parse().ok(),
+      Some(_) => None,
+    };
+
+    match size {
+      Some(size) if size > 0 && RESIZE_SIZES.contains(&(size as u32)) => Ok(Some(size as u32)),
+      _ => Err(Error::Config(format!("Invalid {}, supported sizes are {:?}", key, RESIZE_SIZES))),
+    }
+  }
+
+  /// Reads an integer, which may be given as string (when set via the
+  /// environment).
+  fn get_integer(&self, key: &str) -> Result<Option<i64>> {
+    match self.get(key) {
+      None => Ok(None),
+      Some(&toml::Value::Integer(i)) => Ok(Some(i)),
+      Some(&toml::Value::String(ref s)) if s.parse::<i64>().is_ok() => Ok(s.parse().ok()),
+      Some(_) => Err(Error::Config(format!("Invalid {}, expected a number", key))),
+    }
+  }
+
+  /// Reads a flag, which may be given as boolean or string (when set via
+  /// the environment).
+  fn get_bool(&self, key: &str) -> Result<bool> {
+    match self.get(key) {
+      None => Ok(false),
+      Some(&toml::Value::Boolean(b)) => Ok(b),
+      Some(&toml::Value::String(ref s)) if s == "true" || s == "false" => Ok(s == "true"),
+      Some(_) => Err(Error::Config(format!("Invalid {}, expected true or false", key))),
+    }
+  }
+
+  /// Reads a list of strings, which may be given as comma-separated
+  /// string (when set via the environment).
+  fn get_list(&self, key: &str) -> Result<Vec<String>> {
+    let invalid = || Error::Config(format!("Invalid {}, expected a list of strings", key));
+    match self.get(key) {
+      None => Ok(Vec::new()),
+      Some(&toml::Value::Array(ref values)) => {
+        values.iter()
+          .map(|v| v.as_str().map(String::from).ok_or(invalid()))
+          .collect()
+      },
+      Some(&toml::Value::String(ref s)) => {
+        Ok(s.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect())
+      },
+      Some(_) => Err(invalid()),
+    }
+  }
+
+  /// All effective values together with their origin, sorted by key.
+  pub fn values(&self) -> &BTreeMap<String, (toml::Value, ConfigSource)> {
+    &self.values
+  }
+
+  /// Config files which were merged, in order of precedence.
+  pub fn files(&self) -> &[PathBuf] {
+    &self.files
+  }
+
+  pub fn cameras(&self) -> Vec<String> {
+    let mut cameras: Vec<String> = self.values.keys()
+      .filter(|k| k.starts_with("camera."))
+      .filter_map(|k| k.split('.').nth(1))
+      .map(String::from)
+      .collect();
+    cameras.dedup();
+    cameras
+  }
+
+  /// Applies the profile `[camera.<name>]` on top of the global values.
+  /// Values set via the environment or the command line still take
+  /// precedence over the profile.
+  pub fn for_camera(&self, name: &str) -> Result<ConfigLayers> {
+    if !self.cameras().iter().any(|c| c == name) {
+      return Err(Error::Config(format!("Unknown camera profile `{}`", name)));
+    }
+
+    let prefix = format!("camera.{}.", name);
+    let mut layers = self.clone();
+    for (key, &(ref value, ref source)) in self.values.iter() {
+      if !key.starts_with(&prefix) {
+        continue;
+      }
+
+      let key = &key[prefix.len()..];
+      let overridden = match layers.values.get(key) {
+        Some(&(_, ConfigSource::Env(_))) | Some(&(_, ConfigSource::Cli)) => true,
+        _ => false,
+      };
+      if !overridden {
+        layers.values.insert(key.to_string(), (value.clone(), source.clone()));
+      }
+    }
+
+    layers.camera = Some(name.to_string());
+    Ok(layers)
+  }
+
+  /// The first camera profile whose SSID is contained in `visible`.
+  pub fn detect_camera(&self, visible: &[String]) -> Option<String> {
+    self.cameras().into_iter().find(|name| {
+      self.for_camera(name)
+        .ok()
+        .and_then(|layers| layers.get_str("wifi.ssid").map(String::from))
+        .map_or(false, |ssid| visible.contains(&ssid))
+    })
+  }
+
+  pub fn build(&self) -> Result<Config> {
+    let error_strategy = try!(self.get_str("error_strategy")
       .and_then(ErrorStrategy::from_str)
-      .expect("Invalid error_strategy");
+      .ok_or(Error::Config("Invalid error_strategy".into())));
 
-    let overwrite_strategy = conf.lookup("overwrite_strategy")
-      .expect("`overwrite_strategy` not found in config file")
-      .as_str()
+    let overwrite_strategy = try!(self.get_str("overwrite_strategy")
       .and_then(OverwriteStrategy::from_str)
-      .expect("Invalid overwrite_strategy");
+      .ok_or(Error::Config("Invalid overwrite_strategy".into())));
+
+    let pair_strategy = try!(self.get_str("pair_strategy")
+      .and_then(PairStrategy::from_str)
+      .ok_or(Error::Config("Invalid pair_strategy".into())));
+
+    let duplicate_strategy = try!(self.get_str("duplicate_strategy")
+      .and_then(DuplicateStrategy::from_str)
+      .ok_or(Error::Config("Invalid duplicate_strategy".into())));
+
+    let camera_timezone = try!(self.get_str("camera_timezone")
+      .and_then(CameraTimezone::from_str)
+      .ok_or(Error::Config("Invalid camera_timezone".into())));
+
+    let sync_clock = try!(self.get_bool("sync_clock"));
+
+    let file_times = try!(self.get_str("file_times")
+      .and_then(FileTimes::from_str)
+      .ok_or(Error::Config("Invalid file_times".into())));
 
+    let rename_template = match self.get_str("rename_template") {
+      Some(template) => Some(try!(NameTemplate::parse(template))),
+      None => None,
+    };
 
-    let incremental_dir = conf.lookup("incremental.download_directory")
-      .and_then(toml::Value::as_str)
+    let incremental_dir = self.get_str("incremental.download_directory")
       .map(Path::new)
       .map(expand_tilde);
 
-    let transfer_order_dir = conf.lookup("transfer_order.download_directory")
-      .and_then(toml::Value::as_str)
+    let transfer_order_dir = self.get_str("transfer_order.download_directory")
       .map(Path::new)
       .map(expand_tilde);
 
+    let selection_dir = self.get_str("selection.download_directory")
+      .map(Path::new)
+      .map(expand_tilde)
+      .or(transfer_order_dir.clone());
+
+    let state_dir = xdg_dir("XDG_STATE_HOME", ".local/state").join("omd-transfer");
+    let profile = self.camera.as_ref().map_or("default", |c| &c[..]);
+
+    let incremental_state_file = self.get_str("incremental.state_file")
+      .map(Path::new)
+      .map(expand_tilde)
+      .unwrap_or_else(|| state_dir.join(format!("{}.state", profile)));
+
+    let transfer_order_state_file = self.get_str("transfer_order.state_file")
+      .map(Path::new)
+      .map(expand_tilde)
+      .unwrap_or_else(|| state_dir.join(format!("{}.delivered", profile)));
+
+    let incremental_resize = try!(self.get_resize("incremental.resize"));
+    let transfer_order_resize = try!(self.get_resize("transfer_order.resize"));
+
     info!("transfer_order_dir: {:?}", transfer_order_dir);
     info!("incremental_dir: {:?}", incremental_dir);
 
-    let wifi = conf.lookup("wifi.interface")
-      .and_then(toml::Value::as_str)
+    let wifi = self.get_str("wifi.interface")
       .and_then(|i| {
-        conf.lookup("wifi.ssid")
-          .and_then(toml::Value::as_str)
+        self.get_str("wifi.ssid")
           .map(|s| WifiConfig {
             ssid: s.into(),
             interface: i.into(),
           })
       });
 
-    Config {
+    let preview_cache_dir = self.get_str("preview.cache_directory")
+      .map(Path::new)
+      .map(expand_tilde)
+      .unwrap_or_else(|| xdg_dir("XDG_CACHE_HOME", ".cache").join("omd-transfer"));
+
+    let geotag = match self.get_str("geotag.gpx_directory") {
+      Some(dir) => Some(GeotagConfig {
+        gpx_dir: expand_tilde(Path::new(dir)),
+        time_offset: Duration::seconds(try!(self.get_integer("geotag.time_offset")).unwrap_or(0)),
+        max_gap: Duration::seconds(try!(self.get_integer("geotag.max_gap")).unwrap_or(300)),
+      }),
+      None => None,
+    };
+
+    let xmp_sidecars = try!(self.get_bool("xmp.sidecars"));
+    let xmp_keywords = try!(self.get_list("xmp.keywords"));
+
+    let history_file = self.get_str("history.database")
+      .map(Path::new)
+      .map(expand_tilde)
+      .unwrap_or_else(|| {
+        xdg_dir("XDG_DATA_HOME", ".local/share").join("omd-transfer").join("history.sqlite")
+      });
+
+    Ok(Config {
+      camera: self.camera.clone(),
       download_dir: incremental_dir,
       transfer_order_dir: transfer_order_dir,
+      selection_dir: selection_dir,
+      incremental_state_file: incremental_state_file,
+      transfer_order_state_file: transfer_order_state_file,
+      incremental_resize: incremental_resize,
+      transfer_order_resize: transfer_order_resize,
       error_strategy: error_strategy,
       overwrite_strategy: overwrite_strategy,
+      pair_strategy: pair_strategy,
+      duplicate_strategy: duplicate_strategy,
+      rename_template: rename_template,
+      camera_timezone: camera_timezone,
+      sync_clock: sync_clock,
+      file_times: file_times,
+      geotag: geotag,
+      xmp_sidecars: xmp_sidecars,
+      xmp_keywords: xmp_keywords,
+
+      wifi: wifi,
+      preview_cache_dir: preview_cache_dir,
+      history_file: history_file,
+    })
+  }
+}
 
-      wifi: wifi
+fn env_var_name(key: &str) -> String {
+  format!("OMD_TRANSFER_{}", key.replace(".", "_").to_uppercase())
+}
+
+#[test]
+fn test_env_var_name() {
+  assert_eq!(env_var_name("error_strategy"), "OMD_TRANSFER_ERROR_STRATEGY");
+  assert_eq!(env_var_name("incremental.download_directory"),
+             "OMD_TRANSFER_INCREMENTAL_DOWNLOAD_DIRECTORY");
+}
+
+#[test]
+fn test_layer_precedence() {
+  let mut layers = ConfigLayers::new();
+  assert_eq!(layers.build().unwrap().error_strategy, ErrorStrategy::Abort);
+
+  layers.add_override("error_strategy=continue").unwrap();
+  layers.add_override("incremental.download_directory = \"/tmp/omd\"").unwrap();
+  let config = layers.build().unwrap();
+  assert_eq!(config.error_strategy, ErrorStrategy::Continue);
+  assert_eq!(config.overwrite_strategy, OverwriteStrategy::Skip);
+  assert_eq!(config.download_dir, Some(PathBuf::from("/tmp/omd")));
+  assert_eq!(layers.values()["error_strategy"].1, ConfigSource::Cli);
+
+  assert!(layers.add_override("error_strategy").is_err());
+}
+
+#[test]
+fn test_resize() {
+  let mut layers = ConfigLayers::new();
+  assert_eq!(layers.build().unwrap().incremental_resize, None);
+
+  layers.add_override("incremental.resize=1024").unwrap();
+  layers.set("transfer_order.resize", toml::Value::String("1920".into()),
+             ConfigSource::Env("OMD_TRANSFER_TRANSFER_ORDER_RESIZE".into()));
+  let config = layers.build().unwrap();
+  assert_eq!(config.incremental_resize, Some(1024));
+  assert_eq!(config.transfer_order_resize, Some(1920));
+
+  layers.add_override("incremental.resize=1000").unwrap();
+  assert!(layers.build().is_err());
+}
+
+#[test]
+fn test_xmp_keywords() {
+  let mut layers = ConfigLayers::new();
+  assert!(layers.build().unwrap().xmp_keywords.is_empty());
+
+  layers.add_override("xmp.keywords=[\"olympus\", \"E-M10\"]").unwrap();
+  assert_eq!(layers.build().unwrap().xmp_keywords, vec!["olympus", "E-M10"]);
+
+  layers.set("xmp.keywords", toml::Value::String("olympus, holiday,".into()),
+             ConfigSource::Env("OMD_TRANSFER_XMP_KEYWORDS".into()));
+  assert_eq!(layers.build().unwrap().xmp_keywords, vec!["olympus", "holiday"]);
+
+  layers.add_override("xmp.keywords=[1, 2]").unwrap();
+  assert!(layers.build().is_err());
+}
+
+#[test]
+fn test_camera_timezone() {
+  assert_eq!(CameraTimezone::from_str("local"), Some(CameraTimezone::Local));
+  assert_eq!(CameraTimezone::from_str("+02:00"),
+             Some(CameraTimezone::Fixed(FixedOffset::east(2 * 3600))));
+  assert_eq!(CameraTimezone::from_str("-0530"),
+             Some(CameraTimezone::Fixed(FixedOffset::west(5 * 3600 + 30 * 60))));
+  assert_eq!(CameraTimezone::from_str("Europe/Berlin"), None);
+  assert_eq!(CameraTimezone::from_str("+25:00"), None);
+
+  let mut layers = ConfigLayers::new();
+  assert_eq!(layers.build().unwrap().sync_clock, false);
+  layers.set("sync_clock", toml::Value::String("true".into()),
+             ConfigSource::Env("OMD_TRANSFER_SYNC_CLOCK".into()));
+  assert_eq!(layers.build().unwrap().sync_clock, true);
+  layers.add_override("sync_clock=yes").unwrap();
+  assert!(layers.build().is_err());
+}
+
+#[test]
+fn test_camera_profiles() {
+  let conf: toml::Value = r#"
+    [incremental]
+    download_directory = "/tmp/omd"
+
+    [camera.em5.wifi]
+    ssid = "E-M5MKII-P-1"
+    interface = "wlan0"
+
+    [camera.em10.incremental]
+    download_directory = "/tmp/em10"
+
+    [camera.em10.wifi]
+    ssid = "E-M10MKII-P-2"
+    interface = "wlan0"
+  "#.parse().unwrap();
+  let mut layers = ConfigLayers::new();
+  layers.merge("", &conf, &ConfigSource::File(PathBuf::from("config.toml")));
+
+  assert_eq!(layers.cameras(), vec!["em10".to_string(), "em5".to_string()]);
+  assert_eq!(layers.detect_camera(&["E-M5MKII-P-1".to_string()]), Some("em5".to_string()));
+  assert_eq!(layers.detect_camera(&["Other".to_string()]), None);
+
+  let em5 = layers.for_camera("em5").unwrap().build().unwrap();
+  assert_eq!(em5.camera, Some("em5".to_string()));
+  assert_eq!(em5.download_dir, Some(PathBuf::from("/tmp/omd")));
+  assert_eq!(em5.wifi.unwrap().ssid, "E-M5MKII-P-1");
+  assert!(em5.incremental_state_file.ends_with("omd-transfer/em5.state"));
+  assert!(em5.transfer_order_state_file.ends_with("omd-transfer/em5.delivered"));
+
+  let em10 = layers.for_camera("em10").unwrap().build().unwrap();
+  assert_eq!(em10.download_dir, Some(PathBuf::from("/tmp/em10")));
+
+  layers.add_override("incremental.download_directory=/tmp/cli").unwrap();
+  let em10 = layers.for_camera("em10").unwrap().build().unwrap();
+  assert_eq!(em10.download_dir, Some(PathBuf::from("/tmp/cli")));
+
+  assert!(layers.for_camera("em1").is_err());
+}
+
+impl Config {
+  pub fn from_file<P: AsRef<Path>>(file: P) -> Self {
+    match Config::load(file) {
+      Ok(config) => config,
+      Err(e) => panic!("Failed to load config: {}", e),
     }
   }
 
+  pub fn load<P: AsRef<Path>>(file: P) -> Result<Self> {
+    let mut layers = ConfigLayers::new();
+    try!(layers.add_file(file));
+    layers.build()
+  }
+
   pub fn template() -> &'static str {
     include_str!("../config.template.toml")
   }
+
+  /// The config template with the given values filled in. The `[wifi]`
+  /// section is only enabled if both SSID and interface are given.
+  pub fn template_with(values: &TemplateValues) -> String {
+    let mut template = Config::template().to_string();
+
+    if let Some(ref dir) = values.transfer_order_dir {
+      template = template.replace("download_directory = \"transfer_order/\"",
+                                  &format!("download_directory = {}", toml::Value::String(dir.clone())));
+    }
+
+    if let Some(ref dir) = values.incremental_dir {
+      template = template.replace("download_directory = \"incremental/\"",
+                                  &format!("download_directory = {}", toml::Value::String(dir.clone())));
+    }
+
+    if let (Some(ssid), Some(interface)) = (values.ssid.as_ref(), values.interface.as_ref()) {
+      template = template.replacen("# [wifi]\n# ssid = \"E-M10MKII-P-BHLA00000\"\n# interface = \"wlp3s0\"",
+                                   &format!("[wifi]\nssid = {}\ninterface = {}",
+                                            toml::Value::String(ssid.to_string()),
+                                            toml::Value::String(interface.to_string())),
+                                   1);
+    }
+
+    template
+  }
+}
+
+/// Values filled into the template by `config init`.
+#[derive(Clone, Debug, Default)]
+pub struct TemplateValues {
+  pub ssid: Option<String>,
+  pub interface: Option<String>,
+  pub transfer_order_dir: Option<String>,
+  pub incremental_dir: Option<String>,
+}
+
+#[test]
+fn test_template_with() {
+  let values = TemplateValues {
+    ssid: Some("E-M5MKII-P-1".into()),
+    interface: Some("wlan0".into()),
+    transfer_order_dir: None,
+    incremental_dir: Some("~/Pictures/omd".into()),
+  };
+
+  let conf: toml::Value = Config::template_with(&values).parse().unwrap();
+  assert_eq!(conf.lookup("wifi.ssid").and_then(toml::Value::as_str), Some("E-M5MKII-P-1"));
+  assert_eq!(conf.lookup("wifi.interface").and_then(toml::Value::as_str), Some("wlan0"));
+  assert_eq!(conf.lookup("incremental.download_directory").and_then(toml::Value::as_str),
+             Some("~/Pictures/omd"));
+  assert_eq!(conf.lookup("transfer_order.download_directory").and_then(toml::Value::as_str),
+             Some("transfer_order/"));
+
+  let conf: toml::Value = Config::template_with(&TemplateValues::default()).parse().unwrap();
+  assert!(conf.lookup("wifi").is_none());
+}
+
+/// The XDG base directory named by `var`, falling back to `default`
+/// below the home directory.
+pub fn xdg_dir(var: &str, default: &str) -> PathBuf {
+  match env::var(var) {
+    Ok(ref dir) if Path::new(dir).is_absolute() => PathBuf::from(dir),
+    _ => {
+      let home = env::home_dir().expect("Couldn't get home dir");
+      home.join(default)
+    }
+  }
 }
 
-use std::env;
 fn expand_tilde(p: &Path) -> PathBuf {
   let tilde = Path::new("~");
   if p.starts_with(tilde) {
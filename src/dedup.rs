@@ -0,0 +1,128 @@
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::process::Command;
+
+use config::DuplicateStrategy;
+use error::Result;
+use history::History;
+use manifest::hash_file;
+
+/// An archived file other than `target` with contents `hash`. Files
+/// which changed since they were recorded don't count.
+pub fn find_duplicate(history: &History, hash: &str, target: &Path) -> Result<Option<PathBuf>> {
+  for candidate in try!(history.find_checksum(hash)) {
+    if candidate == target || !candidate.is_file() {
+      continue;
+    }
+
+    if let Ok(ref candidate_hash) = hash_file(&candidate) {
+      if candidate_hash == hash {
+        return Ok(Some(candidate));
+      }
+    }
+  }
+
+  Ok(None)
+}
+
+/// Applies `strategy` to the freshly downloaded `target`, which has the
+/// same contents as `existing`. Returns where the file is stored now.
+pub fn resolve_duplicate(existing: &Path,
+                         target: &Path,
+                         strategy: DuplicateStrategy) -> io::Result<PathBuf> {
+  let mut tmp = target.as_os_str().to_owned();
+  tmp.push(".link");
+  let tmp = PathBuf::from(tmp);
+
+  match strategy {
+    DuplicateStrategy::Keep => return Ok(target.to_path_buf()),
+    DuplicateStrategy::Skip => {
+      try!(fs::remove_file(target));
+      return Ok(existing.to_path_buf());
+    },
+    DuplicateStrategy::Hardlink => try!(fs::hard_link(existing, &tmp)),
+    DuplicateStrategy::Reflink => {
+      // No portable API for this, `cp` knows how to do it on all file
+      // systems supporting it
+      let status = try!(Command::new("cp")
+        .arg("--reflink=always")
+        .arg("--preserve=timestamps")
+        .arg(existing)
+        .arg(&tmp)
+        .status());
+      if !status.success() {
+        let _ = fs::remove_file(&tmp);
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("Reflinking {} failed", existing.display())));
+      }
+    },
+  }
+
+  // Replace the download in one step, so it's never lost
+  try!(fs::rename(&tmp, target));
+  Ok(target.to_path_buf())
+}
+
+/// `resolve_duplicate` for `target`, if it has an archived copy.
+pub fn deduplicate(history: &History,
+                   hash: &str,
+                   target: &Path,
+                   strategy: DuplicateStrategy) -> Result<PathBuf> {
+  if strategy == DuplicateStrategy::Keep {
+    return Ok(target.to_path_buf());
+  }
+
+  match try!(find_duplicate(history, hash, target)) {
+    None => Ok(target.to_path_buf()),
+    Some(existing) => {
+      println!("{} is a copy of {}", target.display(), existing.display());
+      Ok(try!(resolve_duplicate(&existing, target, strategy)))
+    }
+  }
+}
+
+#[test]
+fn test_duplicates() {
+  use std::io::Write;
+  use std::os::unix::fs::MetadataExt;
+  use chrono::NaiveDate;
+  use history::HistoryEntry;
+
+  let dir = ::test_util::TestDir::new("dedup");
+  fs::create_dir_all(dir.join("order")).unwrap();
+  fs::create_dir_all(dir.join("incremental")).unwrap();
+
+  let archived = dir.join("order").join("P4270171.JPG");
+  let target = dir.join("incremental").join("P4270171.JPG");
+  for path in &[&archived, &target] {
+    fs::File::create(path).unwrap().write_all(b"image").unwrap();
+  }
+  let hash = hash_file(&archived).unwrap();
+
+  let history = History::open(dir.join("history.sqlite")).unwrap();
+  let date = NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10);
+  history.record(&HistoryEntry {
+    camera_path: "/DCIM/100OLYMP/P4270171.JPG".to_string(),
+    target: archived.clone(),
+    file_size: 5,
+    camera_date: date,
+    capture_date: None,
+    downloaded_at: date,
+    checksum: Some(hash.clone()),
+    camera: None,
+    model: None,
+    serial: None,
+  }).unwrap();
+
+  assert_eq!(find_duplicate(&history, &hash, &archived).unwrap(), None);
+  assert_eq!(find_duplicate(&history, &hash, &target).unwrap(), Some(archived.clone()));
+
+  let stored = resolve_duplicate(&archived, &target, DuplicateStrategy::Hardlink).unwrap();
+  assert_eq!(stored, target);
+  assert_eq!(fs::metadata(&target).unwrap().ino(), fs::metadata(&archived).unwrap().ino());
+
+  let stored = resolve_duplicate(&archived, &target, DuplicateStrategy::Skip).unwrap();
+  assert_eq!(stored, archived);
+  assert!(!target.exists());
+}
@@ -1,17 +1,36 @@
 use std;
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
 use hyper;
+use rusqlite;
 
 #[derive(Debug)]
 pub enum Error {
   Http(hyper::Error),
   Io(io::Error),
   ProtocolError,
+  Config(String),
+  CorruptState(PathBuf),
+  Database(rusqlite::Error),
 }
 
 pub type Result<T> = std::result::Result<T,Error>;
 
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::Http(ref e) => write!(f, "HTTP error: {}", e),
+      Error::Io(ref e) => write!(f, "IO error: {}", e),
+      Error::ProtocolError => write!(f, "Unexpected response from camera"),
+      Error::Config(ref msg) => write!(f, "Invalid config: {}", msg),
+      Error::CorruptState(ref p) => write!(f, "Corrupt state file {}", p.display()),
+      Error::Database(ref e) => write!(f, "Database error: {}", e),
+    }
+  }
+}
+
 impl From<io::Error> for Error {
   fn from(err: io::Error) -> Self {
       Error::Io(err)
@@ -24,3 +43,8 @@ impl From<hyper::Error> for Error {
   }
 }
 
+impl From<rusqlite::Error> for Error {
+  fn from(err: rusqlite::Error) -> Self {
+      Error::Database(err)
+  }
+}
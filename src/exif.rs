@@ -0,0 +1,318 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader,Read,Seek,SeekFrom};
+use std::path::Path;
+
+use chrono::{Duration,NaiveDateTime};
+
+use error::Result;
+
+const TAG_MODEL: u16 = 0x0110;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+const TAG_BODY_SERIAL_NUMBER: u16 = 0xA431;
+
+const TYPE_ASCII: u16 = 2;
+
+/// The EXIF fields we care about.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ExifData {
+  /// Capture time in camera local time, in whole seconds
+  pub date_time_original: Option<NaiveDateTime>,
+  /// Fraction of a second of `date_time_original`
+  pub subsec_nanos: Option<u32>,
+  pub model: Option<String>,
+  pub serial: Option<String>,
+}
+
+impl ExifData {
+  /// Reads EXIF data from a JPEG or an ORF (which is TIFF-based).
+  pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+    let file = try!(File::open(path));
+    read_exif(BufReader::new(file))
+  }
+
+  /// Capture time including sub-seconds, if known.
+  pub fn capture_time(&self) -> Option<NaiveDateTime> {
+    self.date_time_original.map(|date| {
+      date + Duration::nanoseconds(self.subsec_nanos.unwrap_or(0) as i64)
+    })
+  }
+}
+
+fn invalid(msg: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn read_exif<R: Read + Seek>(mut reader: R) -> Result<ExifData> {
+  let mut magic = [0u8; 2];
+  try!(reader.read_exact(&mut magic));
+
+  let base = match &magic {
+    b"II" | b"MM" => 0,
+    &[0xFF, 0xD8] => match try!(find_jpeg_exif(&mut reader)) {
+      Some(base) => base,
+      None => return Ok(ExifData::default()),
+    },
+    _ => return Err(invalid("Neither JPEG nor TIFF").into()),
+  };
+
+  let mut tiff = try!(Tiff::new(reader, base));
+  let mut exif = ExifData::default();
+
+  let ifd0 = try!(tiff.read_u32(4));
+  let mut exif_ifd = None;
+  for entry in try!(tiff.entries(ifd0)) {
+    match entry.tag {
+      TAG_MODEL => exif.model = tiff.text(&entry),
+      TAG_EXIF_IFD => exif_ifd = Some(try!(tiff.read_u32(entry.value))),
+      _ => (),
+    }
+  }
+
+  if let Some(offset) = exif_ifd {
+    for entry in try!(tiff.entries(offset)) {
+      match entry.tag {
+        TAG_DATE_TIME_ORIGINAL => {
+          exif.date_time_original = tiff.text(&entry).and_then(|date| {
+            NaiveDateTime::parse_from_str(&date, "%Y:%m:%d %H:%M:%S").ok()
+          });
+        },
+        TAG_SUB_SEC_TIME_ORIGINAL => {
+          exif.subsec_nanos = tiff.text(&entry).and_then(|s| parse_subsec(&s));
+        },
+        TAG_BODY_SERIAL_NUMBER => exif.serial = tiff.text(&entry),
+        _ => (),
+      }
+    }
+  }
+
+  Ok(exif)
+}
+
+/// SubSecTime holds the digits after the decimal point, e.g. "05" for
+/// 50 milliseconds.
+fn parse_subsec(s: &str) -> Option<u32> {
+  if s.is_empty() || s.len() > 9 || !s.chars().all(|c| c.is_digit(10)) {
+    return None;
+  }
+  let padded = format!("{:0<9}", s);
+  padded.parse().ok()
+}
+
+/// Walks the JPEG segments up to the APP1 segment holding EXIF data and
+/// returns the offset of its TIFF header.
+fn find_jpeg_exif<R: Read + Seek>(reader: &mut R) -> io::Result<Option<u64>> {
+  loop {
+    let mut marker = [0u8; 2];
+    try!(reader.read_exact(&mut marker));
+    if marker[0] != 0xFF {
+      return Err(invalid("Invalid JPEG segment"));
+    }
+
+    // Start of scan or end of image, no EXIF data
+    if marker[1] == 0xDA || marker[1] == 0xD9 {
+      return Ok(None);
+    }
+
+    let mut length = [0u8; 2];
+    try!(reader.read_exact(&mut length));
+    let length = ((length[0] as u64) << 8 | length[1] as u64).saturating_sub(2);
+
+    match marker[1] {
+      0xE1 => {
+        let mut id = [0u8; 6];
+        try!(reader.read_exact(&mut id));
+        if &id == b"Exif\0\0" {
+          return reader.seek(SeekFrom::Current(0)).map(Some);
+        }
+        try!(reader.seek(SeekFrom::Current(length as i64 - 6)));
+      },
+      _ => {
+        try!(reader.seek(SeekFrom::Current(length as i64)));
+      }
+    }
+  }
+}
+
+struct Entry {
+  tag: u16,
+  kind: u16,
+  count: u32,
+  /// Position of the value (or the offset to it) relative to the TIFF
+  /// header
+  value: u64,
+}
+
+struct Tiff<R> {
+  reader: R,
+  base: u64,
+  /// Length of the stream, offsets and counts are checked against it
+  len: u64,
+  little_endian: bool,
+}
+
+impl<R: Read + Seek> Tiff<R> {
+  fn new(mut reader: R, base: u64) -> io::Result<Self> {
+    let len = try!(reader.seek(SeekFrom::End(0)));
+    try!(reader.seek(SeekFrom::Start(base)));
+    let mut order = [0u8; 2];
+    try!(reader.read_exact(&mut order));
+
+    let little_endian = match &order {
+      b"II" => true,
+      b"MM" => false,
+      _ => return Err(invalid("Invalid TIFF byte order")),
+    };
+
+    Ok(Tiff {
+      reader: reader,
+      base: base,
+      len: len,
+      little_endian: little_endian,
+    })
+  }
+
+  /// Fails unless `len` bytes at `offset` are within the stream.
+  fn check_bounds(&self, offset: u64, len: u64) -> io::Result<()> {
+    match self.base.checked_add(offset).and_then(|start| start.checked_add(len)) {
+      Some(end) if end <= self.len => Ok(()),
+      _ => Err(invalid("EXIF value out of bounds")),
+    }
+  }
+
+  fn read_bytes(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    try!(self.check_bounds(offset, len as u64));
+    try!(self.reader.seek(SeekFrom::Start(self.base + offset)));
+    let mut buf = vec![0u8; len];
+    try!(self.reader.read_exact(&mut buf));
+    Ok(buf)
+  }
+
+  fn read_u16(&mut self, offset: u64) -> io::Result<u16> {
+    let b = try!(self.read_bytes(offset, 2));
+    Ok(if self.little_endian {
+      (b[1] as u16) << 8 | b[0] as u16
+    } else {
+      (b[0] as u16) << 8 | b[1] as u16
+    })
+  }
+
+  fn read_u32(&mut self, offset: u64) -> io::Result<u32> {
+    let b = try!(self.read_bytes(offset, 4));
+    Ok(if self.little_endian {
+      (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+    } else {
+      (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+    })
+  }
+
+  fn entries(&mut self, ifd: u32) -> io::Result<Vec<Entry>> {
+    let ifd = ifd as u64;
+    let count = try!(self.read_u16(ifd));
+    try!(self.check_bounds(ifd + 2, count as u64 * 12));
+
+    let mut entries = Vec::with_capacity(count as usize);
+    for i in 0..count as u64 {
+      let pos = ifd + 2 + i * 12;
+      entries.push(Entry {
+        tag: try!(self.read_u16(pos)),
+        kind: try!(self.read_u16(pos + 2)),
+        count: try!(self.read_u32(pos + 4)),
+        value: pos + 8,
+      });
+    }
+    Ok(entries)
+  }
+
+  fn ascii(&mut self, entry: &Entry) -> io::Result<String> {
+    if entry.kind != TYPE_ASCII {
+      return Err(invalid("Expected ASCII value"));
+    }
+
+    // Values of up to four bytes are stored inline
+    let offset = if entry.count <= 4 {
+      entry.value
+    } else {
+      try!(self.read_u32(entry.value)) as u64
+    };
+
+    let bytes = try!(self.read_bytes(offset, entry.count as usize));
+    let s = String::from_utf8_lossy(&bytes);
+    Ok(s.trim_right_matches('\0').trim().to_string())
+  }
+
+  /// Like `ascii`, but unreadable and empty values are `None`. Cameras
+  /// tend to fill fields they don't use with blanks.
+  fn text(&mut self, entry: &Entry) -> Option<String> {
+    self.ascii(entry).ok().and_then(|s| if s.is_empty() { None } else { Some(s) })
+  }
+}
+
+#[cfg(test)]
+fn test_tiff() -> Vec<u8> {
+  fn le16(v: u16) -> Vec<u8> { vec![v as u8, (v >> 8) as u8] }
+  fn le32(v: u32) -> Vec<u8> { vec![v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8] }
+  fn entry(tag: u16, kind: u16, count: u32, value: u32) -> Vec<u8> {
+    let mut e = le16(tag);
+    e.extend(le16(kind));
+    e.extend(le32(count));
+    e.extend(le32(value));
+    e
+  }
+
+  let mut tiff: Vec<u8> = vec![];
+  tiff.extend(b"II\x2a\x00\x08\x00\x00\x00");
+  // IFD0 at 8: Model at 100, pointer to the EXIF IFD at 38
+  tiff.extend(le16(2));
+  tiff.extend(entry(TAG_MODEL, TYPE_ASCII, 12, 100));
+  tiff.extend(entry(TAG_EXIF_IFD, 4, 1, 38));
+  tiff.extend(le32(0));
+  // EXIF IFD at 38: DateTimeOriginal at 80, SubSecTimeOriginal inline,
+  // serial at 112
+  tiff.extend(le16(3));
+  tiff.extend(entry(TAG_DATE_TIME_ORIGINAL, TYPE_ASCII, 20, 80));
+  tiff.extend(entry(TAG_SUB_SEC_TIME_ORIGINAL, TYPE_ASCII, 3, 0x3530));
+  tiff.extend(entry(TAG_BODY_SERIAL_NUMBER, TYPE_ASCII, 10, 112));
+  tiff.extend(le32(0));
+  tiff.extend(b"2016:04:27 19:39:10\0");
+  tiff.extend(b"E-M10MarkII\0");
+  tiff.extend(b"BHK123456\0");
+  tiff
+}
+
+#[test]
+fn test_read_exif() {
+  use chrono::NaiveDate;
+  use std::io::Cursor;
+
+  let date = NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10);
+
+  let exif = read_exif(Cursor::new(test_tiff())).unwrap();
+  assert_eq!(exif.date_time_original, Some(date));
+  assert_eq!(exif.capture_time(), Some(date + Duration::milliseconds(50)));
+  assert_eq!(exif.model, Some("E-M10MarkII".to_string()));
+  assert_eq!(exif.serial, Some("BHK123456".to_string()));
+
+  let mut jpeg: Vec<u8> = vec![];
+  jpeg.extend(b"\xFF\xD8");
+  jpeg.extend(b"\xFF\xE0\x00\x04\x00\x00");
+  let tiff = test_tiff();
+  let length = tiff.len() + 8;
+  jpeg.extend(&[0xFF, 0xE1, (length >> 8) as u8, length as u8]);
+  jpeg.extend(b"Exif\0\0");
+  jpeg.extend(tiff);
+  jpeg.extend(b"\xFF\xD9");
+
+  let exif = read_exif(Cursor::new(jpeg)).unwrap();
+  assert_eq!(exif.date_time_original, Some(date));
+
+  let exif = read_exif(Cursor::new(b"\xFF\xD8\xFF\xD9".to_vec())).unwrap();
+  assert_eq!(exif.date_time_original, None);
+
+  // A Model value claiming 4 GB
+  let mut tiff = test_tiff();
+  tiff[14..18].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+  assert_eq!(read_exif(Cursor::new(tiff)).unwrap().model, None);
+}
@@ -0,0 +1,247 @@
+use std::fs::{self,File};
+use std::io;
+use std::io::{BufReader,Read};
+use std::fmt::Write;
+use std::path::Path;
+
+use chrono::{DateTime,Duration,NaiveDateTime,Timelike};
+use regex::Regex;
+
+use camera::xml_element;
+use error::Result;
+
+/// A position on earth in WGS 84.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+  pub latitude: f64,
+  pub longitude: f64,
+  /// Meters above sea level
+  pub elevation: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackPoint {
+  /// In UTC
+  pub time: NaiveDateTime,
+  pub position: Position,
+}
+
+/// The points of any number of GPX tracks, ordered by time.
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+  points: Vec<TrackPoint>,
+}
+
+impl Track {
+  pub fn new(mut points: Vec<TrackPoint>) -> Self {
+    points.sort_by_key(|p| p.time);
+    Track {
+      points: points,
+    }
+  }
+
+  /// Reads all `.gpx` files in `dir`. Unreadable files are skipped.
+  pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Self> {
+    let mut points = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+      let path = try!(entry).path();
+      let is_gpx = path.extension()
+        .map_or(false, |ext| ext.to_string_lossy().to_lowercase() == "gpx");
+      if !is_gpx {
+        continue;
+      }
+
+      match File::open(&path).and_then(|file| read_gpx(BufReader::new(file))) {
+        Ok(track) => points.extend(track),
+        Err(e) => warn!("Skipping {}: {}", path.display(), e),
+      }
+    }
+    Ok(Track::new(points))
+  }
+
+  pub fn points(&self) -> &[TrackPoint] {
+    &self.points
+  }
+
+  /// The position at `time` (in UTC), interpolated between the points
+  /// around it. `None` outside the track and in gaps longer than
+  /// `max_gap`, e.g. when the logger was switched off.
+  pub fn locate(&self, time: &NaiveDateTime, max_gap: Duration) -> Option<Position> {
+    let after = match self.points.binary_search_by(|p| p.time.cmp(time)) {
+      Ok(i) => return Some(self.points[i].position),
+      Err(i) => i,
+    };
+    if after == 0 || after == self.points.len() {
+      return None;
+    }
+
+    let (a, b) = (&self.points[after - 1], &self.points[after]);
+    let gap = b.time - a.time;
+    if gap > max_gap {
+      return None;
+    }
+
+    let t = (*time - a.time).num_milliseconds() as f64 / gap.num_milliseconds() as f64;
+    let lerp = |from: f64, to: f64| from + (to - from) * t;
+    Some(Position {
+      latitude: lerp(a.position.latitude, b.position.latitude),
+      longitude: lerp(a.position.longitude, b.position.longitude),
+      elevation: match (a.position.elevation, b.position.elevation) {
+        (Some(from), Some(to)) => Some(lerp(from, to)),
+        (from, to) => from.or(to),
+      },
+    })
+  }
+}
+
+/// Reads the track points of a GPX file. Points without a time or
+/// valid coordinates are skipped, they can't be matched to images.
+pub fn read_gpx<R: Read>(mut source: R) -> io::Result<Vec<TrackPoint>> {
+  lazy_static! {
+    static ref TRKPT: Regex = Regex::new(r"(?s)<trkpt\b([^>]*?)(?:/>|>(.*?)</trkpt>)").unwrap();
+    static ref LAT: Regex = Regex::new(r#"\blat\s*=\s*["']([^"']*)["']"#).unwrap();
+    static ref LON: Regex = Regex::new(r#"\blon\s*=\s*["']([^"']*)["']"#).unwrap();
+  }
+
+  let mut gpx = String::new();
+  try!(source.read_to_string(&mut gpx));
+
+  let mut points = Vec::new();
+  for trkpt in TRKPT.captures_iter(&gpx) {
+    let attributes = trkpt.at(1).unwrap_or("");
+    let coordinate = |re: &Regex| {
+      re.captures(attributes)
+        .and_then(|c| c.at(1))
+        .and_then(|value| value.trim().parse::<f64>().ok())
+    };
+    let body = trkpt.at(2).unwrap_or("");
+    let time = xml_element(body, "time")
+      .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+      .map(|time| time.naive_utc());
+
+    match (coordinate(&LAT), coordinate(&LON), time) {
+      (Some(lat), Some(lon), Some(time)) => points.push(TrackPoint {
+        time: time,
+        position: Position {
+          latitude: lat,
+          longitude: lon,
+          elevation: xml_element(body, "ele").and_then(|ele| ele.parse().ok()),
+        },
+      }),
+      _ => debug!("Skipping track point {}", trkpt.at(0).unwrap_or("")),
+    }
+  }
+
+  Ok(points)
+}
+
+/// Reads a GPS log for the camera, see `to_nmea`. GPX files are
+/// converted, other files are expected to contain NMEA already.
+pub fn read_log<P: AsRef<Path>>(path: P) -> Result<String> {
+  let path = path.as_ref();
+  let is_gpx = path.extension()
+    .map_or(false, |ext| ext.to_string_lossy().to_lowercase() == "gpx");
+  let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData,
+                                             format!("{}: {}", path.display(), msg));
+
+  let mut file = try!(File::open(path));
+  if is_gpx {
+    let points = try!(read_gpx(BufReader::new(file)).map_err(|e| invalid(e.to_string())));
+    return Ok(to_nmea(&Track::new(points).points));
+  }
+
+  let mut log = String::new();
+  try!(file.read_to_string(&mut log));
+  if !log.lines().any(|line| line.starts_with("$GP")) {
+    return Err(invalid("Neither GPX nor NMEA".to_string()).into());
+  }
+  Ok(log)
+}
+
+/// Formats `points` as NMEA 0183 log, the format written by OI.Track
+/// and understood by the cameras. Each point becomes a `$GPRMC` (time
+/// and position) and a `$GPGGA` sentence (elevation).
+pub fn to_nmea(points: &[TrackPoint]) -> String {
+  let mut log = String::new();
+  for point in points {
+    let time = format!("{}.{:02}",
+                       point.time.format("%H%M%S"), point.time.nanosecond() / 10_000_000);
+    let (lat, ns) = nmea_coordinate(point.position.latitude, 2, 'N', 'S');
+    let (lon, ew) = nmea_coordinate(point.position.longitude, 3, 'E', 'W');
+    let elevation = point.position.elevation.map_or(String::new(), |e| format!("{:.1}", e));
+
+    log.push_str(&nmea_sentence(&format!("GPRMC,{},A,{},{},{},{},,,{},,",
+                                         time, lat, ns, lon, ew, point.time.format("%d%m%y"))));
+    log.push_str(&nmea_sentence(&format!("GPGGA,{},{},{},{},{},1,,,{},M,,M,,",
+                                         time, lat, ns, lon, ew, elevation)));
+  }
+  log
+}
+
+/// Wraps `body` into `$...*<checksum>`.
+fn nmea_sentence(body: &str) -> String {
+  let checksum = body.bytes().fold(0, |sum, b| sum ^ b);
+  let mut sentence = String::new();
+  write!(sentence, "${}*{:02X}\r\n", body, checksum).unwrap();
+  sentence
+}
+
+/// Formats a coordinate as degrees with `digits` digits and minutes,
+/// e.g. `5230.0000`.
+fn nmea_coordinate(value: f64, digits: usize, positive: char, negative: char) -> (String, char) {
+  let direction = if value < 0.0 { negative } else { positive };
+  // In 1/10000 minutes, rounded first so 59.99999 carries into the degrees
+  let total = (value.abs() * 600_000.0).round() as u64;
+  let (degrees, minutes) = (total / 600_000, total % 600_000);
+  (format!("{:0width$}{:02}.{:04}", degrees, minutes / 10_000, minutes % 10_000, width = digits),
+   direction)
+}
+
+#[test]
+fn test_track() {
+  use chrono::NaiveDate;
+
+  let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+    <gpx version="1.1" creator="OI.Track" xmlns="http://www.topografix.com/GPX/1/1">
+      <trk><trkseg>
+        <trkpt lat="52.5000" lon="13.4000"><ele>40.0</ele><time>2016-04-27T17:39:00Z</time></trkpt>
+        <trkpt lat="52.5010" lon="13.4020"><ele>50.0</ele><time>2016-04-27T17:39:20Z</time></trkpt>
+        <trkpt lat="52.6000" lon="13.5000"><time>2016-04-27T18:39:20+00:00</time></trkpt>
+        <trkpt lat="52.7000" lon="13.6000"></trkpt>
+      </trkseg></trk>
+    </gpx>"#;
+  let track = Track::new(read_gpx(gpx.as_bytes()).unwrap());
+  assert_eq!(track.points().len(), 3);
+
+  let at = |h, m, s| NaiveDate::from_ymd(2016, 4, 27).and_hms(h, m, s);
+  let max_gap = Duration::minutes(5);
+
+  let position = track.locate(&at(17, 39, 10), max_gap).unwrap();
+  assert!((position.latitude - 52.5005).abs() < 1e-9);
+  assert!((position.longitude - 13.4010).abs() < 1e-9);
+  assert_eq!(position.elevation, Some(45.0));
+
+  assert_eq!(track.locate(&at(17, 39, 20), max_gap).unwrap().elevation, Some(50.0));
+  assert_eq!(track.locate(&at(18, 0, 0), max_gap), None);
+  assert!(track.locate(&at(18, 0, 0), Duration::hours(2)).is_some());
+  assert_eq!(track.locate(&at(17, 0, 0), max_gap), None);
+  assert_eq!(track.locate(&at(19, 0, 0), max_gap), None);
+
+  let gpx = b"<gpx><trkpt lat=\"x\"/><trkpt lat='1.5' lon='2'><time>2016-04-27T17:39:00Z</time></trkpt></gpx>";
+  let points = read_gpx(&gpx[..]).unwrap();
+  assert_eq!(points.len(), 1);
+  assert_eq!(points[0].position, Position { latitude: 1.5, longitude: 2.0, elevation: None });
+
+  assert_eq!(nmea_sentence("GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,"),
+             "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n");
+  let nmea = to_nmea(&track.points()[..1]);
+  let sentences: Vec<&str> = nmea.lines().collect();
+  assert_eq!(sentences.len(), 2);
+  assert!(sentences[0].starts_with("$GPRMC,173900.00,A,5230.0000,N,01324.0000,E,,,270416,,*"));
+  assert!(sentences[1].starts_with("$GPGGA,173900.00,5230.0000,N,01324.0000,E,1,,,40.0,M,,M,,*"));
+
+  assert_eq!(nmea_coordinate(52.99999999, 2, 'N', 'S'), ("5300.0000".to_string(), 'N'));
+  assert_eq!(nmea_coordinate(-33.8688, 2, 'N', 'S'), ("3352.1280".to_string(), 'S'));
+  assert_eq!(nmea_coordinate(-151.2093, 3, 'E', 'W'), ("15112.5580".to_string(), 'W'));
+  assert_eq!(nmea_coordinate(-0.5, 3, 'E', 'W'), ("00030.0000".to_string(), 'W'));
+}
@@ -0,0 +1,278 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use chrono::{Duration,NaiveDate,NaiveDateTime};
+use rusqlite::Connection;
+
+use error::Result;
+use state::DATE_FORMAT;
+
+const SCHEMA: &'static str = "
+  CREATE TABLE IF NOT EXISTS transfers (
+    id            INTEGER PRIMARY KEY,
+    camera_path   TEXT NOT NULL,
+    filename      TEXT NOT NULL,
+    target        TEXT NOT NULL,
+    file_size     INTEGER NOT NULL,
+    camera_date   TEXT NOT NULL,
+    capture_date  TEXT,
+    downloaded_at TEXT NOT NULL,
+    checksum      TEXT,
+    camera        TEXT,
+    model         TEXT,
+    serial        TEXT
+  );
+  CREATE INDEX IF NOT EXISTS transfers_filename ON transfers (filename);
+  CREATE INDEX IF NOT EXISTS transfers_downloaded_at ON transfers (downloaded_at);
+";
+
+/// Schema changes since the first version. `PRAGMA user_version` holds
+/// the number of migrations applied.
+const MIGRATIONS: &'static [&'static str] = &[];
+
+/// Capture dates are stored with sub-seconds, if known.
+const CAPTURE_DATE_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// A single file transferred from the camera. All dates are in UTC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+  /// Full path on the card, e.g. `/DCIM/100OLYMP/P4270171.ORF`
+  pub camera_path: String,
+  pub target: PathBuf,
+  pub file_size: u64,
+  pub camera_date: NaiveDateTime,
+  /// Capture time from EXIF, see `ExifData::capture_time`
+  pub capture_date: Option<NaiveDateTime>,
+  pub downloaded_at: NaiveDateTime,
+  pub checksum: Option<String>,
+  /// The camera profile used for the transfer
+  pub camera: Option<String>,
+  /// The model reported by the camera
+  pub model: Option<String>,
+  /// The body serial number from EXIF
+  pub serial: Option<String>,
+}
+
+impl HistoryEntry {
+  pub fn filename(&self) -> &str {
+    match self.camera_path.rfind('/') {
+      Some(i) => &self.camera_path[i+1..],
+      None => &self.camera_path,
+    }
+  }
+}
+
+/// Restricts the entries returned by `History::query`. Empty fields
+/// match everything, dates are in UTC.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+  /// File name on the card, compared case-insensitively
+  pub filename: Option<String>,
+  /// Only transfers at or after this time
+  pub since: Option<NaiveDateTime>,
+  /// Only transfers before this time
+  pub until: Option<NaiveDateTime>,
+}
+
+/// Record of every file ever transferred, kept in an SQLite database.
+pub struct History {
+  conn: Connection,
+}
+
+impl History {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+      try!(fs::create_dir_all(dir));
+    }
+
+    let conn = try!(Connection::open(path));
+    try!(conn.execute_batch(SCHEMA));
+
+    let version: i64 = try!(conn.query_row("PRAGMA user_version", &[], |row| row.get(0)));
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+      try!(conn.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+                                       migration, i + 1)));
+    }
+
+    Ok(History {
+      conn: conn,
+    })
+  }
+
+  pub fn record(&self, entry: &HistoryEntry) -> Result<()> {
+    let filename = entry.filename().to_string();
+    let target = entry.target.to_string_lossy().into_owned();
+    let camera_date = entry.camera_date.format(DATE_FORMAT).to_string();
+    let capture_date = entry.capture_date.map(|d| d.format(CAPTURE_DATE_FORMAT).to_string());
+    let downloaded_at = entry.downloaded_at.format(DATE_FORMAT).to_string();
+
+    try!(self.conn.execute(
+      "INSERT INTO transfers (camera_path, filename, target, file_size, camera_date,
+                              capture_date, downloaded_at, checksum, camera, model, serial)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+      &[&entry.camera_path, &filename, &target, &(entry.file_size as i64),
+        &camera_date, &capture_date, &downloaded_at, &entry.checksum, &entry.camera,
+        &entry.model, &entry.serial]));
+    Ok(())
+  }
+
+  /// Targets of all transfers of a file with `checksum`, newest first.
+  pub fn find_checksum(&self, checksum: &str) -> Result<Vec<PathBuf>> {
+    self.targets("checksum", checksum)
+  }
+
+  /// Where the file `camera_path` was transferred to, newest first.
+  pub fn targets_of(&self, camera_path: &str) -> Result<Vec<PathBuf>> {
+    self.targets("camera_path", camera_path)
+  }
+
+  fn targets(&self, column: &str, value: &str) -> Result<Vec<PathBuf>> {
+    let mut stmt = try!(self.conn.prepare(&format!(
+      "SELECT target FROM transfers WHERE {} = ?1
+       ORDER BY downloaded_at DESC, id DESC", column)));
+
+    let rows = try!(stmt.query_map(&[&value], |row| {
+      let target: String = row.get(0);
+      PathBuf::from(target)
+    }));
+
+    let mut targets = Vec::new();
+    for row in rows {
+      targets.push(try!(row));
+    }
+    Ok(targets)
+  }
+
+  /// The camera path of the file last transferred to `target`.
+  pub fn source_of(&self, target: &Path) -> Result<Option<String>> {
+    let target = target.to_string_lossy().into_owned();
+    let mut stmt = try!(self.conn.prepare(
+      "SELECT camera_path FROM transfers WHERE target = ?1
+       ORDER BY downloaded_at DESC, id DESC LIMIT 1"));
+
+    let mut rows = try!(stmt.query_map(&[&target], |row| {
+      let camera_path: String = row.get(0);
+      camera_path
+    }));
+
+    match rows.next() {
+      Some(row) => Ok(Some(try!(row))),
+      None => Ok(None),
+    }
+  }
+
+  /// Returns the entries matching `query`, oldest first.
+  pub fn query(&self, query: &HistoryQuery) -> Result<Vec<HistoryEntry>> {
+    let since = query.since.map(|d| d.format(DATE_FORMAT).to_string());
+    let until = query.until.map(|d| d.format(DATE_FORMAT).to_string());
+
+    let mut stmt = try!(self.conn.prepare(
+      "SELECT camera_path, target, file_size, camera_date, downloaded_at,
+              checksum, camera, model, capture_date, serial
+       FROM transfers
+       WHERE (?1 IS NULL OR filename = ?1 COLLATE NOCASE)
+         AND (?2 IS NULL OR downloaded_at >= ?2)
+         AND (?3 IS NULL OR downloaded_at < ?3)
+       ORDER BY downloaded_at, id"));
+
+    let rows = try!(stmt.query_map(&[&query.filename, &since, &until], |row| {
+      let target: String = row.get(1);
+      let file_size: i64 = row.get(2);
+      let camera_date: String = row.get(3);
+      let downloaded_at: String = row.get(4);
+      let capture_date: Option<String> = row.get(8);
+      HistoryEntry {
+        camera_path: row.get(0),
+        target: PathBuf::from(target),
+        file_size: file_size as u64,
+        camera_date: parse_date(&camera_date),
+        capture_date: capture_date.and_then(|d| {
+          NaiveDateTime::parse_from_str(&d, CAPTURE_DATE_FORMAT).ok()
+        }),
+        downloaded_at: parse_date(&downloaded_at),
+        checksum: row.get(5),
+        camera: row.get(6),
+        model: row.get(7),
+        serial: row.get(9),
+      }
+    }));
+
+    let mut entries = Vec::new();
+    for row in rows {
+      entries.push(try!(row));
+    }
+    Ok(entries)
+  }
+}
+
+fn parse_date(s: &str) -> NaiveDateTime {
+  NaiveDateTime::parse_from_str(s, DATE_FORMAT)
+    .unwrap_or_else(|_| NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0))
+}
+
+/// Parses a date bound for `HistoryQuery`: Either `YYYY-MM-DD` or
+/// `YYYY-MM-DDTHH:MM:SS`. A plain date used as upper bound includes the
+/// whole day.
+pub fn parse_date_bound(s: &str, upper: bool) -> Option<NaiveDateTime> {
+  if let Ok(date) = NaiveDateTime::parse_from_str(s, DATE_FORMAT) {
+    return Some(date);
+  }
+
+  NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().map(|date| {
+    let start = date.and_hms(0, 0, 0);
+    if upper { start + Duration::days(1) } else { start }
+  })
+}
+
+#[test]
+fn test_history() {
+  let dir = ::test_util::TestDir::new("history");
+
+  let history = History::open(dir.join("history.sqlite")).unwrap();
+  let entry = |path: &str, downloaded: &str| HistoryEntry {
+    camera_path: path.to_string(),
+    target: PathBuf::from("/tmp/omd").join(&path[path.rfind('/').unwrap()+1..]),
+    file_size: 14845727,
+    camera_date: NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10),
+    capture_date: Some(NaiveDate::from_ymd(2016, 4, 27).and_hms_milli(19, 39, 11, 50)),
+    downloaded_at: parse_date_bound(downloaded, false).unwrap(),
+    checksum: None,
+    camera: Some("em5".to_string()),
+    model: None,
+    serial: Some("BHK123456".to_string()),
+  };
+
+  let first = entry("/DCIM/100OLYMP/P4270171.ORF", "2016-04-28");
+  let mut second = entry("/DCIM/100OLYMP/P4270172.JPG", "2016-05-02T10:00:00");
+  second.capture_date = None;
+  history.record(&first).unwrap();
+  history.record(&second).unwrap();
+
+  let all = history.query(&HistoryQuery::default()).unwrap();
+  assert_eq!(all, vec![first.clone(), second.clone()]);
+
+  let by_name = history.query(&HistoryQuery {
+    filename: Some("p4270171.orf".to_string()),
+    ..HistoryQuery::default()
+  }).unwrap();
+  assert_eq!(by_name, vec![first.clone()]);
+
+  let by_date = history.query(&HistoryQuery {
+    since: parse_date_bound("2016-04-29", false),
+    until: parse_date_bound("2016-05-02", true),
+    ..HistoryQuery::default()
+  }).unwrap();
+  assert_eq!(by_date, vec![second]);
+
+  assert_eq!(history.targets_of("/DCIM/100OLYMP/P4270171.ORF").unwrap(),
+             vec![PathBuf::from("/tmp/omd/P4270171.ORF")]);
+  assert_eq!(history.source_of(Path::new("/tmp/omd/P4270172.JPG")).unwrap(),
+             Some("/DCIM/100OLYMP/P4270172.JPG".to_string()));
+  assert_eq!(history.source_of(Path::new("/tmp/omd/other.JPG")).unwrap(), None);
+
+  // Reopening keeps the entries
+  drop(history);
+  let history = History::open(dir.join("history.sqlite")).unwrap();
+  assert_eq!(history.query(&HistoryQuery::default()).unwrap().len(), 2);
+}
@@ -6,14 +6,48 @@ extern crate hyper;
 extern crate regex;
 extern crate toml;
 extern crate get_if_addrs;
+extern crate termion;
+extern crate rusqlite;
+extern crate crypto;
+extern crate filetime;
 
+pub mod camera;
+pub mod check;
 pub mod config;
+pub mod dedup;
 pub mod error;
+pub mod exif;
+pub mod gpx;
+pub mod history;
+pub mod manifest;
+pub mod naming;
+pub mod pairs;
+pub mod picker;
+pub mod preview;
+pub mod state;
+pub mod timezone;
 pub mod transfer;
+pub mod xmp;
+#[cfg(test)]
+mod test_util;
 
 pub use transfer::*;
 pub use error::*;
 pub use config::*;
+pub use check::*;
+pub use camera::*;
+pub use preview::*;
+pub use picker::*;
+pub use pairs::*;
+pub use state::*;
+pub use timezone::*;
+pub use exif::*;
+pub use dedup::*;
+pub use gpx::*;
+pub use xmp::*;
+pub use history::*;
+pub use manifest::*;
+pub use naming::*;
 
 #[cfg(feature = "dbus")]
 extern crate dbus;
@@ -8,27 +8,43 @@ use omd_transfer::*;
 use getopts::Options;
 use std::{env, fs, io, process};
 use std::io::{Write,ErrorKind};
-use std::path::PathBuf;
+use std::path::{Path,PathBuf};
+use std::sync::atomic::{AtomicBool,Ordering};
 
 fn print_usage(program: &str, opts: Options) {
-  let brief = format!("Usage: {} [options]", program);
+  let brief = format!("Usage: {} [options] [command]\n\n\
+                       Commands:\n    \
+                       status              Show camera model, connection and pending downloads\n    \
+                       pick                Select images to download in a terminal UI\n    \
+                       fetch [PATH...]     Download the given camera paths, see --from\n    \
+                       thumbnails          Cache thumbnails of all images on the card\n    \
+                       screennails         Cache screen-sized previews of all images on the card\n    \
+                       verify [DIR...]     Check downloaded files against their checksums\n    \
+                       history [FILENAME]  List transferred files, see --since and --until\n    \
+                       state rebuild       Rebuild incremental state from downloaded files, see --exif\n    \
+                       clock sync          Set the camera clock to the time of this computer\n    \
+                       gps upload FILE...  Send GPX or NMEA logs to the camera to geotag its images\n    \
+                       config init [PATH]  Write a config template, use --interactive to fill it in\n    \
+                       config check        Validate config, use --ping to contact the camera\n    \
+                       config show         Print the effective config and where values come from",
+                       program);
   print!("{}", opts.usage(&brief));
 }
 
-fn write_config_template() -> io::Result<()> {
+fn write_config_template(target: &Path, template: &str) -> io::Result<()> {
   let file = fs::OpenOptions::new()
     .write(true)
     .create_new(true)
-    .open("config.toml");
+    .open(target);
 
   match file {
     Ok(mut file) => {
-      try!(file.write_all(Config::template().as_bytes()));
+      try!(file.write_all(template.as_bytes()));
       Ok(())
     },
     Err(e) => {
       if e.kind() == ErrorKind::AlreadyExists {
-        println!("config.toml already exists.");
+        println!("{} already exists.", target.display());
         process::exit(1);
       } else {
         Err(e)
@@ -37,6 +53,418 @@ fn write_config_template() -> io::Result<()> {
   }
 }
 
+/// Asks for a value on stderr, so prompting works with `--stdout`.
+/// Suggestions can be picked by their number.
+fn prompt(question: &str, suggestions: &[String], default: Option<&str>) -> Option<String> {
+  let mut stderr = io::stderr();
+  for (i, suggestion) in suggestions.iter().enumerate() {
+    writeln!(stderr, "  [{}] {}", i+1, suggestion).unwrap();
+  }
+  match default {
+    Some(default) => write!(stderr, "{} [{}]: ", question, default).unwrap(),
+    None => write!(stderr, "{}: ", question).unwrap(),
+  }
+
+  let mut line = String::new();
+  io::stdin().read_line(&mut line).expect("Failed to read from stdin");
+  let line = line.trim();
+
+  if line.is_empty() {
+    return default.map(String::from);
+  }
+
+  match line.parse::<usize>() {
+    Ok(n) if n >= 1 && n <= suggestions.len() => Some(suggestions[n-1].clone()),
+    _ => Some(line.to_string()),
+  }
+}
+
+fn init_config(matches: &getopts::Matches, target: &Path) {
+  let mut values = TemplateValues {
+    ssid: matches.opt_str("ssid"),
+    interface: matches.opt_str("interface"),
+    transfer_order_dir: matches.opt_str("transfer-order-dir"),
+    incremental_dir: matches.opt_str("incremental-dir"),
+  };
+
+  if matches.opt_present("i") {
+    if values.interface.is_none() {
+      values.interface = prompt("Wifi interface (empty to skip)", &wifi_interfaces(), None);
+    }
+    if values.ssid.is_none() {
+      if let Some(ref interface) = values.interface {
+        values.ssid = prompt("Camera SSID", &wifi_networks(interface), None);
+      }
+    }
+    if values.transfer_order_dir.is_none() {
+      values.transfer_order_dir = prompt("Transfer order directory", &[], Some("transfer_order/"));
+    }
+    if values.incremental_dir.is_none() {
+      values.incremental_dir = prompt("Incremental directory", &[], Some("incremental/"));
+    }
+  }
+
+  let template = Config::template_with(&values);
+  if matches.opt_present("stdout") {
+    print!("{}", template);
+    return;
+  }
+
+  write_config_template(target, &template).expect("Failed to write config template");
+  println!("Wrote config template to {}", target.display());
+}
+
+#[cfg(feature = "dbus")]
+fn wifi_interfaces() -> Vec<String> {
+  wifi::interfaces().unwrap_or(vec![])
+}
+
+#[cfg(feature = "dbus")]
+fn wifi_networks(interface: &str) -> Vec<String> {
+  wifi::configured_ssids(interface).unwrap_or(vec![])
+}
+
+#[cfg(not(feature = "dbus"))]
+fn wifi_interfaces() -> Vec<String> {
+  vec![]
+}
+
+#[cfg(not(feature = "dbus"))]
+fn wifi_networks(_interface: &str) -> Vec<String> {
+  vec![]
+}
+
+/// Runs the checks, with `ping` also on the camera network like a
+/// transfer would.
+fn run_check(layers: &ConfigLayers, ping: bool) -> bool {
+  let passed = print_checks(&check_config(layers));
+  if !ping || !passed {
+    return passed;
+  }
+
+  let config = layers.build().expect("Config was checked");
+  let responded = AtomicBool::new(false);
+  run_transfers(&config, || {
+    responded.store(print_checks(&[check_camera()]), Ordering::SeqCst)
+  });
+  responded.load(Ordering::SeqCst)
+}
+
+fn print_checks(checks: &[Check]) -> bool {
+  for check in checks.iter() {
+    match check.outcome {
+      Ok(()) => println!("[ OK ] {}", check.description),
+      Err(ref e) => println!("[FAIL] {}: {}", check.description, e),
+    }
+  }
+  checks.iter().all(Check::passed)
+}
+
+fn show_config(layers: &ConfigLayers) {
+  let assignments: Vec<(String, &ConfigSource)> = layers.values().iter()
+    .map(|(key, &(ref value, ref source))| (format!("{} = {}", key, value), source))
+    .collect();
+  let width = assignments.iter().map(|&(ref a, _)| a.len()).max().unwrap_or(0);
+
+  for (assignment, source) in assignments {
+    println!("{:<width$}  # {}", assignment, source, width = width);
+  }
+}
+
+fn print_status(config: &Config) {
+  match camera_status(config) {
+    Ok(status) => {
+      println!("Model:           {}", status.info.model);
+      println!("Connection mode: {}", status.info.connect_mode);
+      println!("Images:          {}", status.images);
+      println!("Transfer order:  {}", status.transfer_order);
+      println!("Pending:         {} files ({})",
+               status.pending_files, format_size(status.pending_bytes));
+    },
+    Err(e) => println!("Failed to query camera status: {}", e),
+  }
+}
+
+fn cache_previews(config: &Config, preview: Preview) {
+  match fetch_previews(config, preview) {
+    Ok(paths) => println!("{} previews cached in {}",
+                          paths.len(), config.preview_cache_dir.display()),
+    Err(e) => println!("Failed to fetch previews: {}", e),
+  }
+}
+
+fn pick_and_transfer(config: &Config) {
+  let transfer = match SelectionTransfer::from_config(config) {
+    Some(transfer) => transfer,
+    None => {
+      println!("No download directory for selected items configured");
+      return;
+    }
+  };
+
+  match pick_items(config) {
+    Ok(Some(items)) => {
+      info!("Starting to execute selection transfer");
+      execute_transfer(transfer.with_items(items), config).unwrap();
+    },
+    Ok(None) => println!("Cancelled"),
+    Err(e) => println!("Failed to list items: {}", e),
+  }
+}
+
+fn fetch_paths(config: &Config, paths: Vec<String>) {
+  match ListTransfer::from_config(config) {
+    Some(transfer) => {
+      info!("Starting to execute list transfer");
+      execute_transfer(transfer.with_paths(paths), config).unwrap();
+    },
+    None => println!("No download directory for selected items configured"),
+  }
+}
+
+fn read_paths(matches: &getopts::Matches) -> io::Result<Vec<String>> {
+  let mut paths: Vec<String> = matches.free.iter().skip(1).cloned().collect();
+  match matches.opt_str("from") {
+    Some(ref file) if file == "-" => {
+      let stdin = io::stdin();
+      paths.extend(try!(read_path_list(stdin.lock())));
+    },
+    Some(file) => {
+      let file = try!(fs::File::open(file));
+      paths.extend(try!(read_path_list(io::BufReader::new(file))));
+    },
+    None => (),
+  }
+  Ok(paths)
+}
+
+fn rebuild_state(config: &Config, verify_exif: bool) {
+  let transfer = match IncrementalTransfer::from_config(config) {
+    Some(transfer) => transfer,
+    None => {
+      println!("No incremental transfer configured");
+      return;
+    }
+  };
+
+  let result = list_camera_items()
+    .and_then(|items| transfer.rebuild_state(&items, verify_exif));
+
+  match result {
+    Ok(Some(date)) => println!("Wrote {} to {}",
+                               utc_to_local(&date), transfer.state_file().display()),
+    Ok(None) => println!("No images from the camera found in {}",
+                         transfer.download_directory().display()),
+    Err(e) => println!("Failed to rebuild state: {}", e),
+  }
+}
+
+fn sync_clock(config: &Config) {
+  match sync_camera_clock(config) {
+    Ok(sync) => {
+      match sync.drift {
+        Some(drift) => println!("Camera clock was {}", format_drift(drift.num_seconds())),
+        None => println!("Camera didn't report its time"),
+      }
+      println!("Set camera clock to the current time at UTC{}", sync.offset);
+    },
+    Err(e) => println!("Failed to set camera clock: {}", e),
+  }
+}
+
+fn upload_logs(paths: &[PathBuf]) {
+  match upload_gps_logs(paths) {
+    Ok(sentences) => println!("Sent {} NMEA sentences to the camera", sentences),
+    Err(e) => println!("Failed to upload GPS log: {}", e),
+  }
+}
+
+/// Re-hashes the files in `dirs`, or all download directories if empty.
+/// Returns whether all files are intact.
+fn verify_downloads(config: &Config, dirs: Vec<PathBuf>) -> bool {
+  let mut dirs = if dirs.is_empty() {
+    vec![config.download_dir.clone(),
+         config.transfer_order_dir.clone(),
+         config.selection_dir.clone()]
+      .into_iter()
+      .filter_map(|d| d)
+      .collect()
+  } else {
+    dirs
+  };
+  dirs.sort();
+  dirs.dedup();
+
+  let mut passed = true;
+  for dir in dirs {
+    let manifest = match Manifest::load(&dir) {
+      Ok(manifest) => manifest,
+      Err(e) => {
+        println!("Failed to read manifest of {}: {}", dir.display(), e);
+        passed = false;
+        continue;
+      }
+    };
+
+    // Files moved out of the directory are fine, damaged ones aren't
+    let (mut intact, mut missing, mut damaged) = (0, 0, 0);
+    for (filename, result) in manifest.verify() {
+      let problem = match result {
+        Verification::Ok => { intact += 1; continue },
+        Verification::Missing => { missing += 1; continue },
+        Verification::Mismatch => "checksum mismatch".to_string(),
+        Verification::Unreadable(e) => format!("unreadable: {}", e),
+      };
+      println!("{}: {}", dir.join(filename).display(), problem);
+      damaged += 1;
+    }
+
+    println!("{}: {} intact, {} damaged, {} missing", dir.display(), intact, damaged, missing);
+    passed = passed && damaged == 0;
+  }
+
+  passed
+}
+
+fn show_history(config: &Config, filename: Option<&str>, matches: &getopts::Matches) {
+  let bound = |name: &str, upper: bool| {
+    // Dates on the command line are local, the history is in UTC
+    matches.opt_str(name).map(|s| match parse_date_bound(&s, upper) {
+      Some(date) => local_to_utc(&date),
+      None => {
+        println!("Invalid date for --{}: {}", name, s);
+        process::exit(1);
+      }
+    })
+  };
+
+  let query = HistoryQuery {
+    filename: filename.map(String::from),
+    since: bound("since", false),
+    until: bound("until", true),
+  };
+
+  let entries = History::open(&config.history_file)
+    .and_then(|history| history.query(&query));
+  let entries = match entries {
+    Ok(entries) => entries,
+    Err(e) => {
+      println!("Failed to read history {}: {}", config.history_file.display(), e);
+      process::exit(1);
+    }
+  };
+
+  for entry in &entries {
+    let camera = entry.camera.as_ref().or(entry.model.as_ref());
+    println!("{}  {} -> {} ({}{})",
+             utc_to_local(&entry.downloaded_at).format("%Y-%m-%d %H:%M:%S"),
+             entry.camera_path,
+             entry.target.display(),
+             format_size(entry.file_size),
+             camera.map(|c| format!(", {}", c)).unwrap_or(String::new()));
+  }
+
+  if entries.is_empty() {
+    println!("No matching transfers");
+  }
+}
+
+fn format_drift(seconds: i64) -> String {
+  let abs = seconds.abs();
+  let amount = if abs >= 3600 {
+    format!("{}h {}m {}s", abs / 3600, abs / 60 % 60, abs % 60)
+  } else if abs >= 60 {
+    format!("{}m {}s", abs / 60, abs % 60)
+  } else {
+    format!("{}s", abs)
+  };
+
+  match seconds {
+    0 => "correct".to_string(),
+    s if s > 0 => format!("{} ahead", amount),
+    _ => format!("{} behind", amount),
+  }
+}
+
+fn format_size(bytes: u64) -> String {
+  let units = ["B", "KiB", "MiB", "GiB"];
+  let mut size = bytes as f64;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < units.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1} {}", size, units[unit])
+}
+
+/// Loads the config for `camera`. Only commands that contact the camera
+/// `detect` it, the others fall back to the global settings.
+fn load_layers(config_file: Option<&Path>,
+               overrides: &[String],
+               camera: Option<String>,
+               detect: bool) -> ConfigLayers {
+  let layers = ConfigLayers::load(config_file, overrides)
+    .and_then(|layers| match select_camera(&layers, camera, detect) {
+      Some(camera) => layers.for_camera(&camera),
+      None => Ok(layers),
+    });
+
+  match layers {
+    Ok(layers) => layers,
+    Err(e) => {
+      println!("{}", e);
+      process::exit(1);
+    }
+  }
+}
+
+/// Picks the camera profile to use: The one given via `--camera`, the
+/// only one configured or, with `detect`, the one whose network is in
+/// range.
+fn select_camera(layers: &ConfigLayers, requested: Option<String>, detect: bool) -> Option<String> {
+  let cameras = layers.cameras();
+  if requested.is_some() || cameras.len() <= 1 {
+    return requested.or(cameras.into_iter().next());
+  }
+  if !detect {
+    return None;
+  }
+
+  match detect_camera(layers) {
+    Some(camera) => {
+      println!("Detected camera {}", camera);
+      Some(camera)
+    },
+    None => {
+      println!("Couldn't detect camera, select one of {} via --camera",
+               cameras.join(", "));
+      process::exit(1);
+    }
+  }
+}
+
+#[cfg(feature = "dbus")]
+fn detect_camera(layers: &ConfigLayers) -> Option<String> {
+  let mut interfaces: Vec<String> = layers.cameras().iter()
+    .filter_map(|camera| layers.for_camera(camera).ok())
+    .filter_map(|layers| layers.build().ok())
+    .filter_map(|config| config.wifi.map(|w| w.interface))
+    .collect();
+  interfaces.sort();
+  interfaces.dedup();
+
+  let visible: Vec<String> = interfaces.iter()
+    .filter_map(|i| wifi::visible_ssids(i).ok())
+    .flat_map(|ssids| ssids.into_iter())
+    .collect();
+  layers.detect_camera(&visible)
+}
+
+#[cfg(not(feature = "dbus"))]
+fn detect_camera(_layers: &ConfigLayers) -> Option<String> {
+  None
+}
+
 fn main() {
   env_logger::init().unwrap();
 
@@ -44,8 +472,22 @@ fn main() {
   let program = args[0].clone();
 
   let mut opts = Options::new();
-  opts.optopt("c", "config", "Config file to use. Defaults to ~/.herbstmove.toml", "FILE");
+  opts.optopt("c", "config", "Config file to use. Defaults to ~/.omd-transfer.toml", "FILE");
+  opts.optmulti("s", "set", "Override a config value", "KEY=VALUE");
+  opts.optopt("", "from", "File with camera paths for `fetch`, - for stdin", "FILE");
+  opts.optopt("", "since", "Only list transfers from this date on in `history`", "YYYY-MM-DD");
+  opts.optopt("", "until", "Only list transfers up to this date in `history`", "YYYY-MM-DD");
+  opts.optflag("", "exif", "Also match EXIF dates during `state rebuild`");
+  opts.optopt("", "resize", "Download JPEGs resized to this width instead of originals", "SIZE");
+  opts.optopt("", "camera", "Camera profile to use. Detected by SSID if omitted", "NAME");
   opts.optflag("t", "write-template", "Print config template to stdout");
+  opts.optflag("", "stdout", "Print the config of `config init` instead of writing it");
+  opts.optflag("i", "interactive", "Ask for values during `config init`");
+  opts.optopt("", "ssid", "Camera SSID for `config init`", "SSID");
+  opts.optopt("", "interface", "Wifi interface for `config init`", "INTERFACE");
+  opts.optopt("", "transfer-order-dir", "Transfer order directory for `config init`", "DIR");
+  opts.optopt("", "incremental-dir", "Incremental directory for `config init`", "DIR");
+  opts.optflag("p", "ping", "Contact the camera during `config check`");
   opts.optflag("h", "help", "print this help menu");
   let matches = match opts.parse(&args[1..]) {
     Ok(m) => { m }
@@ -58,32 +500,131 @@ fn main() {
   }
 
   if matches.opt_present("t") {
-    write_config_template().expect("Failed to write config template");
-    println!("Wrote config template to config.toml");
+    print!("{}", Config::template());
     return;
   }
 
-  let config_file: PathBuf = matches.opt_str("c")
+  let config_file: Option<PathBuf> = matches.opt_str("c")
     .or(env::var("OMD_TRANSFER_CONFIG").ok())
-    .map(PathBuf::from)
-    .unwrap_or({
-      let mut homedir = env::home_dir().expect("Couldn't get home dir");
-      homedir.push(".omd-transfer.toml");
-      homedir
-    });
-  let config_file = config_file.canonicalize()
-    .expect("Couldn't canonicalize config_file");
+    .map(PathBuf::from);
+  let config_file = config_file.as_ref().map(|p| p.as_path());
+  let mut overrides = matches.opt_strs("s");
+  if let Some(size) = matches.opt_str("resize") {
+    overrides.push(format!("transfer_order.resize={}", size));
+    overrides.push(format!("incremental.resize={}", size));
+  }
+  let camera = matches.opt_str("camera");
 
-  if !config_file.exists() {
-    println!("File {} not found", config_file.display());
-    return;
+  let command: Vec<&str> = matches.free.iter().map(|s| &s[..]).collect();
+  match (command.get(0).cloned(), command.get(1).cloned()) {
+    (None, _) => (),
+    (Some("status"), None) => (),
+    (Some("pick"), None) => (),
+    (Some("fetch"), _) => (),
+    (Some("history"), _) if command.len() <= 2 => (),
+    (Some("verify"), _) => (),
+    (Some("state"), Some("rebuild")) => (),
+    (Some("clock"), Some("sync")) => (),
+    (Some("gps"), Some("upload")) if command.len() > 2 => (),
+    (Some("thumbnails"), None) => (),
+    (Some("screennails"), None) => (),
+    (Some("config"), Some("init")) => {
+      let target = command.get(2).map(PathBuf::from)
+        .or(config_file.map(Path::to_path_buf))
+        .unwrap_or_else(user_config_path);
+      init_config(&matches, &target);
+      return;
+    },
+    (Some("config"), Some("check")) => {
+      let ping = matches.opt_present("p");
+      let layers = load_layers(config_file, &overrides, camera, ping);
+      let passed = run_check(&layers, ping);
+      process::exit(if passed { 0 } else { 1 });
+    },
+    (Some("config"), Some("show")) => {
+      show_config(&load_layers(config_file, &overrides, camera, false));
+      return;
+    },
+    _ => {
+      print_usage(&program, opts);
+      process::exit(1);
+    }
   }
 
-  println!("Using config from {}", config_file.display());
+  // The history and manifests are there without the camera
+  let offline = match command.get(0).cloned() {
+    Some("history") | Some("verify") => true,
+    _ => false,
+  };
+  let layers = load_layers(config_file, &overrides, camera, !offline);
+  for file in layers.files() {
+    println!("Using config from {}", file.display());
+  }
+
+  let config = match layers.build() {
+    Ok(config) => config,
+    Err(e) => {
+      println!("{}", e);
+      process::exit(1);
+    }
+  };
 
-  let config = Config::from_file(&config_file);
+  match command.get(0).cloned() {
+    Some("verify") => {
+      let dirs: Vec<PathBuf> = command[1..].iter().map(PathBuf::from).collect();
+      let passed = verify_downloads(&config, dirs);
+      process::exit(if passed { 0 } else { 1 });
+    },
+    Some("history") => {
+      show_history(&config, command.get(1).cloned(), &matches);
+      return;
+    },
+    Some("status") => {
+      run_transfers(&config, || print_status(&config));
+      return;
+    },
+    Some("pick") => {
+      run_transfers(&config, || pick_and_transfer(&config));
+      return;
+    },
+    Some("fetch") => {
+      let paths = read_paths(&matches).expect("Failed to read paths");
+      run_transfers(&config, || fetch_paths(&config, paths));
+      return;
+    },
+    Some("state") => {
+      run_transfers(&config, || rebuild_state(&config, matches.opt_present("exif")));
+      return;
+    },
+    Some("clock") => {
+      run_transfers(&config, || sync_clock(&config));
+      return;
+    },
+    Some("gps") => {
+      let paths: Vec<PathBuf> = command[2..].iter().map(PathBuf::from).collect();
+      run_transfers(&config, || upload_logs(&paths));
+      return;
+    },
+    Some("thumbnails") => {
+      run_transfers(&config, || cache_previews(&config, Preview::Thumbnail));
+      return;
+    },
+    Some("screennails") => {
+      run_transfers(&config, || cache_previews(&config, Preview::Screennail));
+      return;
+    },
+    _ => (),
+  }
 
   let f = || {
+    // Images on the card were taken with the clock before the sync
+    let mut transfer_config = config.clone();
+    if config.sync_clock {
+      transfer_config.camera_timezone = current_timezone(config.camera_timezone);
+      sync_clock(&config);
+    }
+    let config = transfer_config;
+
     OrderTransfer::from_config(&config).map(|transfer| {
       info!("Starting to execute transfer order");
       execute_transfer(transfer, &config).unwrap();
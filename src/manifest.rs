@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::fs::{File,OpenOptions};
+use std::io;
+use std::io::{BufRead,BufReader,Read,Write};
+use std::path::{Path,PathBuf};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+/// Name of the manifest in each download directory. The format is the
+/// one of `sha256sum`, so `sha256sum -c .omd-transfer.sha256` works too.
+pub const MANIFEST_FILE: &'static str = ".omd-transfer.sha256";
+
+/// Hashes everything written through it.
+pub struct HashingWriter<W> {
+  inner: W,
+  hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+  pub fn new(inner: W) -> Self {
+    HashingWriter {
+      inner: inner,
+      hasher: Sha256::new(),
+    }
+  }
+
+  pub fn get_ref(&self) -> &W {
+    &self.inner
+  }
+
+  pub fn hash(&mut self) -> String {
+    self.hasher.result_str()
+  }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = try!(self.inner.write(buf));
+    self.hasher.input(&buf[..n]);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+  let mut file = try!(File::open(path));
+  let mut hasher = Sha256::new();
+  let mut buf = [0u8; 64 * 1024];
+  loop {
+    let n = try!(file.read(&mut buf));
+    if n == 0 {
+      break;
+    }
+    hasher.input(&buf[..n]);
+  }
+  Ok(hasher.result_str())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+  Ok,
+  /// The file's contents changed since it was downloaded
+  Mismatch,
+  Missing,
+  /// The file couldn't be read
+  Unreadable(String),
+}
+
+/// Checksums of the files downloaded to a directory.
+pub struct Manifest {
+  dir: PathBuf,
+  entries: BTreeMap<String, String>,
+}
+
+impl Manifest {
+  /// A missing manifest is empty.
+  pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+    let dir = dir.as_ref().to_path_buf();
+    let mut entries = BTreeMap::new();
+
+    match File::open(dir.join(MANIFEST_FILE)) {
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+      Err(e) => return Err(e),
+      Ok(f) => {
+        for line in BufReader::new(f).lines() {
+          let line = try!(line);
+          // Files which were replaced appear more than once, the last
+          // line is the current one
+          if let Some((filename, hash)) = parse_line(&line) {
+            entries.insert(filename, hash);
+          }
+        }
+      }
+    }
+
+    Ok(Manifest {
+      dir: dir,
+      entries: entries,
+    })
+  }
+
+  pub fn path(&self) -> PathBuf {
+    self.dir.join(MANIFEST_FILE)
+  }
+
+  /// Adds the hash of `filename` to the manifest. The line is appended so
+  /// a crash can't damage existing entries.
+  pub fn add(&mut self, filename: &str, hash: &str) -> io::Result<()> {
+    let mut f = try!(OpenOptions::new().create(true).append(true).open(self.path()));
+    try!(write!(f, "{}  {}\n", hash, filename));
+    try!(f.sync_all());
+    self.entries.insert(filename.to_string(), hash.to_string());
+    Ok(())
+  }
+
+  pub fn verify(&self) -> Vec<(String, Verification)> {
+    self.entries.iter().map(|(filename, expected)| {
+      let path = self.dir.join(filename);
+      let result = if !path.exists() {
+        Verification::Missing
+      } else {
+        match hash_file(&path) {
+          Ok(ref hash) if hash == expected => Verification::Ok,
+          Ok(_) => Verification::Mismatch,
+          Err(e) => Verification::Unreadable(e.to_string()),
+        }
+      };
+      (filename.clone(), result)
+    }).collect()
+  }
+}
+
+fn parse_line(line: &str) -> Option<(String, String)> {
+  let mut parts = line.splitn(2, "  ");
+  match (parts.next(), parts.next()) {
+    (Some(hash), Some(filename)) if hash.len() == 64 && !filename.is_empty() => {
+      Some((filename.to_string(), hash.to_string()))
+    },
+    _ => None,
+  }
+}
+
+#[test]
+fn test_manifest() {
+  let dir = ::test_util::TestDir::new("manifest");
+
+  let mut writer = HashingWriter::new(File::create(dir.join("a.jpg")).unwrap());
+  writer.write_all(b"abc").unwrap();
+  let hash = writer.hash();
+  assert_eq!(hash, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+  assert_eq!(hash_file(dir.join("a.jpg")).unwrap(), hash);
+
+  File::create(dir.join("b.jpg")).unwrap().write_all(b"b").unwrap();
+  let mut manifest = Manifest::load(&dir).unwrap();
+  manifest.add("a.jpg", &hash).unwrap();
+  manifest.add("b.jpg", &hash_file(dir.join("b.jpg")).unwrap()).unwrap();
+  manifest.add("c.jpg", &hash).unwrap();
+
+  File::create(dir.join("b.jpg")).unwrap().write_all(b"rot").unwrap();
+
+  let manifest = Manifest::load(&dir).unwrap();
+  assert_eq!(manifest.verify(), vec![
+    ("a.jpg".to_string(), Verification::Ok),
+    ("b.jpg".to_string(), Verification::Mismatch),
+    ("c.jpg".to_string(), Verification::Missing),
+  ]);
+}
@@ -0,0 +1,276 @@
+use std::collections::{HashMap,HashSet};
+use std::fmt::Write;
+
+use chrono::{NaiveDate,NaiveDateTime};
+
+use error::{Error,Result};
+use pairs::pair_key;
+use transfer::TransferItem;
+
+const DEFAULT_DATE_FORMAT: &'static str = "%Y%m%d_%H%M%S";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+  Literal(String),
+  /// Capture date with a strftime format
+  Date(String),
+  Camera,
+  Counter,
+  Name,
+  Ext,
+}
+
+/// Values available to a `NameTemplate`.
+pub struct NameFields<'a> {
+  pub date: NaiveDateTime,
+  /// Camera profile or model
+  pub camera: &'a str,
+  /// The camera's file name, e.g. `P4270171.ORF`
+  pub filename: &'a str,
+}
+
+impl<'a> NameFields<'a> {
+  fn stem(&self) -> &'a str {
+    match self.filename.rfind('.') {
+      Some(i) => &self.filename[..i],
+      None => self.filename,
+    }
+  }
+
+  fn ext(&self) -> &'a str {
+    match self.filename.rfind('.') {
+      Some(i) => &self.filename[i+1..],
+      None => "",
+    }
+  }
+
+  /// The running number of the camera, the last four digits of the
+  /// file name.
+  fn counter(&self) -> &'a str {
+    let stem = self.stem();
+    let digits = stem.chars().rev().take_while(|c| c.is_digit(10)).take(4).count();
+    if digits == 0 { stem } else { &stem[stem.len() - digits..] }
+  }
+}
+
+/// Template for the names of downloaded files, e.g.
+/// `{date:%Y/%m/%Y%m%d_%H%M%S}_{camera}_{counter}.{ext}`. Placeholders
+/// are `{date}` (optionally with a strftime format), `{camera}`,
+/// `{counter}`, `{name}` (the camera's name without extension) and
+/// `{ext}`. `/` creates subdirectories.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+  parts: Vec<Part>,
+}
+
+impl NameTemplate {
+  pub fn parse(template: &str) -> Result<Self> {
+    let invalid = |msg: &str| Error::Config(format!("{} in rename template `{}`", msg, template));
+
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+      if start > 0 {
+        parts.push(Part::Literal(rest[..start].to_string()));
+      }
+
+      let end = try!(rest[start..].find('}').ok_or(invalid("Unclosed `{`"))) + start;
+      let placeholder = &rest[start+1..end];
+      parts.push(match placeholder {
+        "date" => Part::Date(DEFAULT_DATE_FORMAT.to_string()),
+        "camera" => Part::Camera,
+        "counter" => Part::Counter,
+        "name" => Part::Name,
+        "ext" => Part::Ext,
+        p if p.starts_with("date:") => {
+          let format = &p["date:".len()..];
+          if !is_valid_format(format) {
+            return Err(invalid("Invalid date format"));
+          }
+          Part::Date(format.to_string())
+        },
+        _ => return Err(invalid(&format!("Unknown placeholder `{{{}}}`", placeholder))),
+      });
+      rest = &rest[end+1..];
+    }
+    if !rest.is_empty() {
+      parts.push(Part::Literal(rest.to_string()));
+    }
+
+    let template = NameTemplate {
+      parts: parts,
+    };
+
+    // Names must stay inside the download directory
+    let sample = template.render(&NameFields {
+      date: NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10),
+      camera: "camera",
+      filename: "P4270171.ORF",
+    });
+    if sample.starts_with('/') || sample.split('/').any(|c| c == ".." || c.is_empty()) {
+      return Err(invalid("Invalid path"));
+    }
+
+    Ok(template)
+  }
+
+  pub fn render(&self, fields: &NameFields) -> String {
+    let mut name = String::new();
+    for part in &self.parts {
+      match *part {
+        Part::Literal(ref s) => name.push_str(s),
+        Part::Date(ref format) => name.push_str(&fields.date.format(format).to_string()),
+        Part::Camera => name.push_str(&sanitize(fields.camera)),
+        Part::Counter => name.push_str(fields.counter()),
+        Part::Name => name.push_str(fields.stem()),
+        Part::Ext => name.push_str(fields.ext()),
+      }
+    }
+    name
+  }
+}
+
+/// Names the items of a transfer. RAW+JPEG siblings get the same stem
+/// and names are never given out twice.
+pub struct Renamer<'a> {
+  template: &'a NameTemplate,
+  camera: String,
+  /// Siblings of each pair
+  pairs: HashMap<(String, String), Vec<TransferItem>>,
+  /// Date of each pair, from the first sibling named
+  dates: HashMap<(String, String), NaiveDateTime>,
+  /// Collision suffix chosen for each pair
+  suffixes: HashMap<(String, String), usize>,
+  taken: HashSet<String>,
+}
+
+impl<'a> Renamer<'a> {
+  pub fn new(template: &'a NameTemplate, camera: String, items: &[TransferItem]) -> Self {
+    let mut pairs: HashMap<(String, String), Vec<TransferItem>> = HashMap::new();
+    for item in items {
+      pairs.entry(key(item)).or_insert_with(Vec::new).push(item.clone());
+    }
+
+    Renamer {
+      template: template,
+      camera: camera,
+      pairs: pairs,
+      dates: HashMap::new(),
+      suffixes: HashMap::new(),
+      taken: HashSet::new(),
+    }
+  }
+
+  fn render(&self, item: &TransferItem, n: usize) -> String {
+    // Siblings written a second apart still share the date
+    let date = self.dates.get(&key(item)).cloned().unwrap_or(item.date);
+
+    let name = self.template.render(&NameFields {
+      date: date,
+      camera: &self.camera,
+      filename: &item.filename,
+    });
+    if n == 0 { name } else { with_suffix(&name, n) }
+  }
+
+  /// The relative path for `item` taken at `date`, the capture time in
+  /// local time. `is_free` tells whether a name can be used for an item,
+  /// i.e. it doesn't belong to a different file.
+  pub fn name<F>(&mut self, item: &TransferItem, date: &NaiveDateTime, is_free: F) -> String
+    where F: Fn(&str, &TransferItem) -> bool {
+    let key = key(item);
+    self.dates.entry(key.clone()).or_insert(*date);
+    let usable = |renamer: &Self, item: &TransferItem, n: usize| {
+      let name = renamer.render(item, n);
+      !renamer.taken.contains(&name) && is_free(&name, item)
+    };
+
+    // The first item of a pair picks a suffix which works for all
+    // siblings
+    let mut n = match self.suffixes.get(&key) {
+      Some(&n) => n,
+      None => {
+        let siblings = self.pairs.get(&key).cloned().unwrap_or_else(|| vec![item.clone()]);
+        (0..).find(|&n| siblings.iter().all(|s| usable(self, s, n))).unwrap()
+      }
+    };
+    while !usable(self, item, n) {
+      n += 1;
+    }
+
+    let name = self.render(item, n);
+    self.taken.insert(name.clone());
+    self.suffixes.insert(key, n);
+    name
+  }
+}
+
+fn key(item: &TransferItem) -> (String, String) {
+  let (parent, stem) = pair_key(item);
+  (parent.to_string(), stem.to_string())
+}
+
+/// Whether `format` can be used to format a `NaiveDateTime`. Formats
+/// with a time zone can't.
+fn is_valid_format(format: &str) -> bool {
+  let date = NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10);
+  let mut s = String::new();
+  write!(s, "{}", date.format(format)).is_ok()
+}
+
+fn sanitize(s: &str) -> String {
+  s.chars()
+    .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+    .collect()
+}
+
+/// Inserts `_<n>` before the extension of `name` to resolve a collision.
+pub fn with_suffix(name: &str, n: usize) -> String {
+  let file_start = name.rfind('/').map_or(0, |i| i + 1);
+  match name[file_start..].rfind('.') {
+    Some(i) => format!("{}_{}{}", &name[..file_start + i], n, &name[file_start + i..]),
+    None => format!("{}_{}", name, n),
+  }
+}
+
+#[test]
+fn test_name_template() {
+  let fields = |filename| NameFields {
+    date: NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10),
+    camera: "E-M10 Mark II",
+    filename: filename,
+  };
+
+  let template = NameTemplate::parse("{date}_{camera}_{counter}.{ext}").unwrap();
+  assert_eq!(template.render(&fields("P4270171.ORF")), "20160427_193910_E-M10_Mark_II_0171.ORF");
+  assert_eq!(template.render(&fields("P4270171.JPG")), "20160427_193910_E-M10_Mark_II_0171.JPG");
+
+  let template = NameTemplate::parse("{date:%Y/%m}/{name}.{ext}").unwrap();
+  assert_eq!(template.render(&fields("P4270171.ORF")), "2016/04/P4270171.ORF");
+
+  assert!(NameTemplate::parse("{date}_{serial}").is_err());
+  assert!(NameTemplate::parse("{date").is_err());
+  assert!(NameTemplate::parse("{date:%z}").is_err());
+  assert!(NameTemplate::parse("../{name}").is_err());
+  assert!(NameTemplate::parse("/{name}").is_err());
+
+  assert_eq!(with_suffix("2016/04/P4270171.ORF", 1), "2016/04/P4270171_1.ORF");
+  assert_eq!(with_suffix("2016.04/P4270171", 2), "2016.04/P4270171_2");
+
+  // Siblings share the stem, even if written a second apart and renamed
+  // because of a collision
+  let items: Vec<TransferItem> = vec![
+    "/DCIM/100OLYMP,P4270171.ORF,14845727,0,18587,40165",
+    "/DCIM/100OLYMP,P4270171.JPG,7935748,0,18587,40166",
+    "/DCIM/100OLYMP,P4270172.JPG,8023494,0,18587,40167",
+  ].into_iter().map(TransferItem::from_row).collect();
+  let template = NameTemplate::parse("{date}.{ext}").unwrap();
+  let mut renamer = Renamer::new(&template, "em10".to_string(), &items);
+  let names: Vec<String> = items.iter()
+    .map(|item| {
+      let captured = item.date + ::chrono::Duration::hours(1);
+      renamer.name(item, &captured, |name, _| name != "20160427_203910.JPG")
+    })
+    .collect();
+  assert_eq!(names, vec!["20160427_203910_1.ORF", "20160427_203910_1.JPG", "20160427_203914.JPG"]);
+}
@@ -0,0 +1,105 @@
+use hyper::Client;
+
+use config::PairStrategy;
+use error::Result;
+use transfer::*;
+
+/// Files of the same shot share folder and stem, e.g.
+/// `/DCIM/100OLYMP/P4270171.ORF` and `/DCIM/100OLYMP/P4270171.JPG`.
+pub fn pair_key(item: &TransferItem) -> (&str, &str) {
+  let stem = match item.filename.rfind('.') {
+    Some(i) => &item.filename[..i],
+    None => &item.filename,
+  };
+  (&item.parent, stem)
+}
+
+/// Adds the siblings found in `listing` of every item, directly after
+/// the item itself.
+fn complete_pairs(items: Vec<TransferItem>, listing: &[TransferItem]) -> Vec<TransferItem> {
+  let mut result: Vec<TransferItem> = Vec::new();
+
+  for item in items {
+    if result.contains(&item) {
+      continue;
+    }
+
+    let siblings: Vec<TransferItem> = listing.iter()
+      .filter(|other| pair_key(other) == pair_key(&item) && **other != item)
+      .filter(|other| !result.contains(other))
+      .cloned()
+      .collect();
+
+    result.push(item);
+    result.extend(siblings);
+  }
+
+  result
+}
+
+/// Drops JPEGs which have a RAW sibling in `items`.
+fn prefer_raw(items: Vec<TransferItem>) -> Vec<TransferItem> {
+  let raws: Vec<(String, String)> = items.iter()
+    .filter(|item| item.is_raw())
+    .map(|item| {
+      let (parent, stem) = pair_key(item);
+      (parent.to_string(), stem.to_string())
+    })
+    .collect();
+
+  items.into_iter()
+    .filter(|item| {
+      let (parent, stem) = pair_key(item);
+      !(item.is_jpeg() && raws.iter().any(|&(ref p, ref s)| p == parent && s == stem))
+    })
+    .collect()
+}
+
+/// Applies `strategy` to the items of a transfer. Folders containing
+/// items are listed to find their siblings.
+pub fn apply_pair_strategy(client: &Client,
+                           items: Vec<TransferItem>,
+                           strategy: PairStrategy) -> Result<Vec<TransferItem>> {
+  if strategy == PairStrategy::None {
+    return Ok(items);
+  }
+
+  let mut parents: Vec<String> = items.iter().map(|i| i.parent.clone()).collect();
+  parents.sort();
+  parents.dedup();
+
+  let mut listing = Vec::new();
+  for parent in parents {
+    let endpoint = format!("get_imglist.cgi?DIR={}", parent);
+    listing.extend(try!(request_list(&client, &endpoint)));
+  }
+
+  let items = complete_pairs(items, &listing);
+  match strategy {
+    PairStrategy::PreferRaw => Ok(prefer_raw(items)),
+    _ => Ok(items),
+  }
+}
+
+#[test]
+fn test_pairs() {
+  let listing: Vec<TransferItem> = vec![
+    "/DCIM/100OLYMP,P4270171.ORF,14845727,0,18587,40165",
+    "/DCIM/100OLYMP,P4270171.JPG,7935748,0,18587,40165",
+    "/DCIM/100OLYMP,P4270172.JPG,8023494,0,18587,40167",
+    "/DCIM/100OLYMP,P4270173.ORF,14894106,0,18587,40217",
+    "/DCIM/100OLYMP,P4270173.JPG,8203245,0,18587,40217",
+  ].into_iter().map(TransferItem::from_row).collect();
+
+  let filenames = |items: &[TransferItem]| -> Vec<String> {
+    items.iter().map(|i| i.filename.clone()).collect()
+  };
+
+  let order = vec![listing[1].clone(), listing[2].clone(), listing[3].clone()];
+  let completed = complete_pairs(order, &listing);
+  assert_eq!(filenames(&completed),
+             vec!["P4270171.JPG", "P4270171.ORF", "P4270172.JPG", "P4270173.ORF", "P4270173.JPG"]);
+
+  assert_eq!(filenames(&prefer_raw(completed)),
+             vec!["P4270171.ORF", "P4270172.JPG", "P4270173.ORF"]);
+}
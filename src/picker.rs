@@ -0,0 +1,240 @@
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use hyper::Client;
+use termion;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+use config::Config;
+use error::Result;
+use history::History;
+use transfer::*;
+
+enum Row {
+  Header(String),
+  Item(usize),
+}
+
+/// Terminal UI to select items for download. Items are grouped by
+/// folder and date.
+pub struct Picker {
+  items: Vec<TransferItem>,
+  downloaded: Vec<bool>,
+  selected: Vec<bool>,
+  rows: Vec<Row>,
+  cursor: usize,
+}
+
+impl Picker {
+  /// `downloaded` tells which of `items` were downloaded before.
+  pub fn new(items: Vec<TransferItem>, downloaded: Vec<bool>) -> Self {
+    let mut items: Vec<(TransferItem, bool)> = items.into_iter().zip(downloaded).collect();
+    items.sort_by(|&(ref a, _), &(ref b, _)| {
+      (&a.parent, a.date, &a.filename).cmp(&(&b.parent, b.date, &b.filename))
+    });
+    let (items, downloaded): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+
+    let mut rows = Vec::new();
+    let mut group = None;
+    for (i, item) in items.iter().enumerate() {
+      let header = format!("{} - {}", item.parent, item.date.format("%Y-%m-%d"));
+      if group.as_ref() != Some(&header) {
+        rows.push(Row::Header(header.clone()));
+        group = Some(header);
+      }
+      rows.push(Row::Item(i));
+    }
+
+    Picker {
+      selected: vec![false; items.len()],
+      items: items,
+      downloaded: downloaded,
+      rows: rows,
+      cursor: 0,
+    }
+  }
+
+  pub fn selection(&self) -> Vec<TransferItem> {
+    self.items.iter()
+      .zip(self.selected.iter())
+      .filter(|&(_, &selected)| selected)
+      .map(|(item, _)| item.clone())
+      .collect()
+  }
+
+  /// Handles a key press. Returns `Some(true)` when the selection was
+  /// confirmed and `Some(false)` when the picker was cancelled.
+  fn handle(&mut self, key: Key) -> Option<bool> {
+    let last = self.items.len().saturating_sub(1);
+    match key {
+      Key::Up | Key::Char('k') => self.cursor = self.cursor.saturating_sub(1),
+      Key::Down | Key::Char('j') => self.cursor = ::std::cmp::min(self.cursor + 1, last),
+      Key::PageUp => self.cursor = self.cursor.saturating_sub(10),
+      Key::PageDown => self.cursor = ::std::cmp::min(self.cursor + 10, last),
+      Key::Char(' ') => {
+        if let Some(selected) = self.selected.get_mut(self.cursor) {
+          *selected = !*selected;
+        }
+        self.cursor = ::std::cmp::min(self.cursor + 1, last);
+      },
+      Key::Char('n') => {
+        // Select everything not downloaded yet
+        for (selected, downloaded) in self.selected.iter_mut().zip(self.downloaded.iter()) {
+          *selected = !*downloaded;
+        }
+      },
+      Key::Char('c') => {
+        for selected in self.selected.iter_mut() {
+          *selected = false;
+        }
+      },
+      Key::Char('\n') => return Some(true),
+      Key::Char('q') | Key::Esc | Key::Ctrl('c') => return Some(false),
+      _ => (),
+    }
+    None
+  }
+
+  fn render<W: Write>(&self, out: &mut W, width: u16, height: u16) -> io::Result<()> {
+    try!(write!(out, "{}{}", termion::clear::All, termion::cursor::Goto(1, 1)));
+    try!(write!(out, "{} of {} selected. space: toggle, n: select new, c: clear, \
+                      enter: download, q: quit",
+                self.selected.iter().filter(|&&s| s).count(),
+                self.items.len()));
+
+    // Scroll so the cursor stays visible below the status line
+    let visible = (height as usize).saturating_sub(1);
+    let cursor_row = self.rows.iter()
+      .position(|row| row_is(row, self.cursor))
+      .unwrap_or(0);
+    let first = (cursor_row + 1).saturating_sub(visible);
+
+    for (line, row) in self.rows.iter().skip(first).take(visible).enumerate() {
+      try!(write!(out, "{}", termion::cursor::Goto(1, line as u16 + 2)));
+      let text = match *row {
+        Row::Header(ref header) => format!("-- {}", header),
+        Row::Item(i) => {
+          let item = &self.items[i];
+          format!("  [{}] {:<14} {:>10} {} {}",
+                  if self.selected[i] { "x" } else { " " },
+                  item.filename,
+                  format!("{:.1} MiB", item.file_size as f64 / (1024.0 * 1024.0)),
+                  item.date.format("%H:%M:%S"),
+                  if self.downloaded[i] { "(downloaded)" } else { "" })
+        }
+      };
+      let text: String = text.chars().take(width as usize).collect();
+
+      if row_is(row, self.cursor) {
+        try!(write!(out, "{}{}{}", termion::style::Invert, text, termion::style::Reset));
+      } else {
+        try!(write!(out, "{}", text));
+      }
+    }
+
+    out.flush()
+  }
+
+  /// Runs the picker on the terminal. Returns the selected items, or
+  /// `None` if the user cancelled.
+  pub fn run(mut self) -> io::Result<Option<Vec<TransferItem>>> {
+    let stdin = io::stdin();
+    let mut stdout = try!(io::stdout().into_raw_mode());
+    try!(write!(stdout, "{}", termion::cursor::Hide));
+
+    let (width, height) = try!(termion::terminal_size());
+    try!(self.render(&mut stdout, width, height));
+
+    let mut confirmed = false;
+    for key in stdin.keys() {
+      match self.handle(try!(key)) {
+        Some(c) => {
+          confirmed = c;
+          break;
+        },
+        None => {
+          let (width, height) = try!(termion::terminal_size());
+          try!(self.render(&mut stdout, width, height));
+        }
+      }
+    }
+
+    try!(write!(stdout, "{}{}{}",
+                termion::clear::All,
+                termion::cursor::Goto(1, 1),
+                termion::cursor::Show));
+
+    if confirmed {
+      Ok(Some(self.selection()))
+    } else {
+      Ok(None)
+    }
+  }
+}
+
+fn row_is(row: &Row, cursor: usize) -> bool {
+  match *row {
+    Row::Item(i) => i == cursor,
+    Row::Header(_) => false,
+  }
+}
+
+/// Whether `item` already exists in one of the download directories,
+/// under its own name or, according to the history, a new one.
+fn is_downloaded(config: &Config, history: Option<&History>, item: &TransferItem) -> bool {
+  let dirs: Vec<&PathBuf> = vec![config.download_dir.as_ref(),
+                                 config.transfer_order_dir.as_ref(),
+                                 config.selection_dir.as_ref()]
+    .into_iter()
+    .filter_map(|d| d)
+    .collect();
+
+  let renamed = history
+    .and_then(|h| h.targets_of(&item.path()).ok())
+    .map_or(false, |targets| targets.iter().any(|t| t.exists()));
+
+  renamed || dirs.iter().any(|dir| dir.join(&item.filename).exists())
+}
+
+/// Lists the card and lets the user pick items. Returns `None` if the
+/// user cancelled.
+pub fn pick_items(config: &Config) -> Result<Option<Vec<TransferItem>>> {
+  let client = Client::new();
+  let items = try!(list_items(&client));
+  let history = History::open(&config.history_file).ok();
+  let downloaded = items.iter()
+    .map(|item| is_downloaded(config, history.as_ref(), item))
+    .collect();
+
+  let selection = try!(Picker::new(items, downloaded).run());
+  Ok(selection)
+}
+
+#[test]
+fn test_picker() {
+  let items = vec![
+    TransferItem::from_row("/DCIM/101OLYMP,P5010001.JPG,7935748,0,18593,40165"),
+    TransferItem::from_row("/DCIM/100OLYMP,P4270172.JPG,8023494,0,18587,40167"),
+    TransferItem::from_row("/DCIM/100OLYMP,P4270171.JPG,7935748,0,18587,40165"),
+  ];
+  let mut picker = Picker::new(items, vec![false, true, false]);
+
+  // Sorted by folder and date, one header per group
+  assert_eq!(picker.items[0].filename, "P4270171.JPG");
+  assert_eq!(picker.rows.len(), 5);
+
+  picker.handle(Key::Char(' '));
+  assert_eq!(picker.cursor, 1);
+  assert_eq!(picker.selection().len(), 1);
+
+  picker.handle(Key::Char('n'));
+  let selection: Vec<String> = picker.selection().into_iter().map(|i| i.filename).collect();
+  assert_eq!(selection, vec!["P4270171.JPG", "P5010001.JPG"]);
+
+  picker.handle(Key::Char('c'));
+  assert!(picker.selection().is_empty());
+  assert_eq!(picker.handle(Key::Char('\n')), Some(true));
+}
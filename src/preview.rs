@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::PathBuf;
+
+use hyper::Client;
+
+use config::*;
+use error::{Error,Result};
+use transfer::*;
+
+/// The preview images offered by the camera.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preview {
+  Thumbnail,
+  Screennail,
+}
+
+impl Preview {
+  fn directory(&self) -> &'static str {
+    match *self {
+      Preview::Thumbnail => "thumbnails",
+      Preview::Screennail => "screennails",
+    }
+  }
+}
+
+/// Where the preview of `item` is cached, e.g.
+/// `<cache>/thumbnails/DCIM/100OLYMP/P4270171.ORF.jpg`.
+pub fn preview_path(config: &Config, item: &TransferItem, preview: Preview) -> PathBuf {
+  let mut path = config.preview_cache_dir.clone();
+  if let Some(ref camera) = config.camera {
+    path.push(camera);
+  }
+  path.push(preview.directory());
+  path.push(item.parent.trim_left_matches('/'));
+  path.push(format!("{}.jpg", item.filename));
+  path
+}
+
+/// Fetches the previews of all items on the card which aren't cached
+/// yet. Returns the paths of all cached previews.
+pub fn fetch_previews(config: &Config, preview: Preview) -> Result<Vec<PathBuf>> {
+  let client = Client::new();
+  let items = try!(list_items(&client));
+
+  // Used for formatting
+  let pad_width = format!("{}", items.len()).len();
+
+  let mut paths = Vec::new();
+  for (i, item) in items.iter().enumerate() {
+    let target = preview_path(config, item, preview);
+
+    if !target.exists() {
+      println!("[{i:>pad$}/{len}] Fetching {kind} of {filename}",
+               pad      = pad_width,
+               i        = i+1,
+               len      = items.len(),
+               kind     = preview.directory(),
+               filename = item.filename);
+
+      try!(fs::create_dir_all(target.parent().unwrap()));
+      let result = match preview {
+        Preview::Thumbnail => item.download_thumbnail(&client, &target),
+        Preview::Screennail => item.download_screennail(&client, &target),
+      };
+
+      match result {
+        Ok(()) => (),
+        // Movies and some RAW-only items have no previews
+        Err(Error::ProtocolError) => {
+          warn!("Camera has no {} of {}", preview.directory(), item.filename);
+          continue;
+        },
+        Err(e) => {
+          warn!("Failed to fetch preview of {}: {}", item.filename, e);
+          if config.error_strategy == ErrorStrategy::Abort {
+            return Err(e);
+          }
+          continue;
+        }
+      }
+    }
+
+    paths.push(target);
+  }
+
+  Ok(paths)
+}
+
+#[test]
+fn test_preview_path() {
+  let mut config = ConfigLayers::new().build().unwrap();
+  config.preview_cache_dir = PathBuf::from("/tmp/cache");
+  let item = TransferItem::from_row("/DCIM/100OLYMP,P4270171.ORF,14845727,0,18587,40165");
+
+  assert_eq!(preview_path(&config, &item, Preview::Thumbnail),
+             PathBuf::from("/tmp/cache/thumbnails/DCIM/100OLYMP/P4270171.ORF.jpg"));
+
+  config.camera = Some("em5".into());
+  assert_eq!(preview_path(&config, &item, Preview::Screennail),
+             PathBuf::from("/tmp/cache/em5/screennails/DCIM/100OLYMP/P4270171.ORF.jpg"));
+}
@@ -0,0 +1,203 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read,Write};
+use std::path::{Path,PathBuf};
+
+use chrono::NaiveDateTime;
+
+use error::{Error,Result};
+use timezone::local_to_utc;
+
+pub const DATE_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%S";
+
+/// Writes `contents` to a temporary file next to `path`, syncs it and
+/// renames it over `path`, so `path` either has the old or the new
+/// contents, even after a crash.
+pub fn write_atomically<P: AsRef<Path>>(path: P, contents: &[u8]) -> io::Result<()> {
+  let path = path.as_ref();
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+
+  {
+    let mut f = try!(File::create(&tmp));
+    try!(f.write_all(contents));
+    try!(f.sync_all());
+  }
+
+  try!(fs::rename(&tmp, path));
+
+  // Persist the rename itself
+  if let Some(dir) = path.parent() {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    try!(File::open(dir).and_then(|d| d.sync_all()));
+  }
+
+  Ok(())
+}
+
+/// State of the incremental transfer: The date of the newest item
+/// downloaded, in UTC. The previous state is kept as backup.
+pub struct StateFile {
+  path: PathBuf,
+}
+
+impl StateFile {
+  pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+    StateFile {
+      path: path.into(),
+    }
+  }
+
+  pub fn path(&self) -> &PathBuf {
+    &self.path
+  }
+
+  pub fn backup_path(&self) -> PathBuf {
+    let mut backup = self.path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+  }
+
+  pub fn read(&self) -> Result<Option<NaiveDateTime>> {
+    read_date(&self.path)
+  }
+
+  /// Like `read`, but falls back to the backup if the state file is
+  /// unreadable. The backup is restored in that case.
+  pub fn read_or_recover(&self) -> Result<Option<NaiveDateTime>> {
+    let err = match self.read() {
+      Ok(date) => return Ok(date),
+      Err(e) => e,
+    };
+
+    warn!("{}, trying backup", err);
+    match read_date(&self.backup_path()) {
+      Ok(Some(date)) => {
+        println!("Recovered state from {}", self.backup_path().display());
+        try!(self.write(&date));
+        Ok(Some(date))
+      },
+      _ => Err(err),
+    }
+  }
+
+  /// Moves the state file and its backup from `legacy` to this
+  /// location, unless a state file exists here already. Returns whether
+  /// anything was moved.
+  pub fn migrate_from(&self, legacy: &Path) -> io::Result<bool> {
+    if self.path.exists() || !legacy.exists() || legacy == self.path.as_path() {
+      return Ok(false);
+    }
+
+    if let Some(dir) = self.path.parent() {
+      try!(fs::create_dir_all(dir));
+    }
+
+    let legacy = StateFile::new(legacy);
+    let backup = legacy.backup_path();
+    if backup.exists() {
+      try!(move_file(&backup, &self.backup_path()));
+    }
+    try!(move_file(legacy.path(), &self.path));
+    Ok(true)
+  }
+
+  pub fn write(&self, date: &NaiveDateTime) -> io::Result<()> {
+    // Keep the last good state around in case the state file gets
+    // damaged anyway
+    if let Ok(Some(_)) = self.read() {
+      try!(fs::copy(&self.path, self.backup_path()));
+    }
+
+    if let Some(dir) = self.path.parent() {
+      try!(fs::create_dir_all(dir));
+    }
+
+    let contents = format!("{}Z", date.format(DATE_FORMAT));
+    write_atomically(&self.path, contents.as_bytes())
+  }
+}
+
+/// Renames `from` to `to`, falling back to copying if they are on
+/// different file systems.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+  if fs::rename(from, to).is_ok() {
+    return Ok(());
+  }
+
+  try!(fs::copy(from, to));
+  try!(File::open(to).and_then(|f| f.sync_all()));
+  fs::remove_file(from)
+}
+
+fn read_date(path: &Path) -> Result<Option<NaiveDateTime>> {
+  use std::io::ErrorKind;
+  match File::open(path) {
+    Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(Error::Io(e)),
+    Ok(mut f) => {
+      let mut buf = String::new();
+      try!(f.read_to_string(&mut buf).map_err(|_| Error::CorruptState(path.to_path_buf())));
+      let buf = buf.trim();
+      let date = try!(NaiveDateTime::parse_from_str(buf.trim_right_matches('Z'), DATE_FORMAT)
+        .map_err(|_| Error::CorruptState(path.to_path_buf())));
+      debug!("read date from state file: {}", date);
+
+      // Older versions stored camera time, which was taken to be local
+      if buf.ends_with('Z') {
+        Ok(Some(date))
+      } else {
+        Ok(Some(local_to_utc(&date)))
+      }
+    }
+  }
+}
+
+#[test]
+fn test_state_recovery() {
+  use chrono::NaiveDate;
+
+  let dir = ::test_util::TestDir::new("state");
+
+  let state = StateFile::new(dir.join("omd-downloader.state"));
+  assert_eq!(state.read().unwrap(), None);
+
+  let first = NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10);
+  let second = NaiveDate::from_ymd(2016, 4, 28).and_hms(10, 0, 0);
+  state.write(&first).unwrap();
+  state.write(&second).unwrap();
+  assert_eq!(state.read().unwrap(), Some(second));
+
+  // Simulate a write interrupted by a crash
+  File::create(state.path()).unwrap();
+  assert!(state.read().is_err());
+  assert_eq!(state.read_or_recover().unwrap(), Some(first));
+  assert_eq!(state.read().unwrap(), Some(first));
+
+  // Files of older versions have local times
+  File::create(state.path()).unwrap().write_all(b"2016-04-27T19:39:10\n").unwrap();
+  assert_eq!(state.read().unwrap(), Some(local_to_utc(&first)));
+}
+
+#[test]
+fn test_state_migration() {
+  use chrono::NaiveDate;
+
+  let dir = ::test_util::TestDir::new("migration");
+
+  let date = NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10);
+  let legacy = StateFile::new(dir.join("omd-downloader.state"));
+  legacy.write(&date).unwrap();
+  legacy.write(&date).unwrap();
+
+  let state = StateFile::new(dir.join("state").join("default.state"));
+  assert!(state.migrate_from(legacy.path()).unwrap());
+  assert_eq!(state.read().unwrap(), Some(date));
+  assert!(state.backup_path().exists());
+  assert!(!legacy.path().exists());
+  assert!(!legacy.backup_path().exists());
+
+  // Nothing left to migrate
+  assert!(!state.migrate_from(legacy.path()).unwrap());
+}
@@ -0,0 +1,40 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path,PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory for a test, removed again when dropped. Names are
+/// unique per process, so concurrent test runs don't collide.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+  pub fn new(name: &str) -> Self {
+    let dir = ::std::env::temp_dir().join(format!("omd-transfer-test-{}-{}-{}", name, process::id(),
+                                                  COUNTER.fetch_add(1, Ordering::SeqCst)));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    TestDir(dir)
+  }
+}
+
+impl Deref for TestDir {
+  type Target = Path;
+  fn deref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl AsRef<Path> for TestDir {
+  fn as_ref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Drop for TestDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
@@ -0,0 +1,202 @@
+use chrono::{Duration,FixedOffset,Local,NaiveDateTime,Offset,TimeZone,UTC};
+use hyper::Client;
+use hyper::header::Date;
+
+use config::{CameraTimezone,Config};
+use error::Result;
+use transfer::{BASE_URL,request};
+
+/// Converts camera dates, which don't have a timezone, to UTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraClock {
+  /// `None` for the timezone of this computer
+  offset: Option<FixedOffset>,
+}
+
+impl CameraClock {
+  pub fn local() -> Self {
+    CameraClock {
+      offset: None,
+    }
+  }
+
+  pub fn fixed(offset: FixedOffset) -> Self {
+    CameraClock {
+      offset: Some(offset),
+    }
+  }
+
+  /// The clock for `timezone`. With `CameraTimezone::Auto` the camera is
+  /// asked for its time, falling back to local time.
+  pub fn resolve(timezone: CameraTimezone, client: &Client) -> Self {
+    match timezone {
+      CameraTimezone::Local => CameraClock::local(),
+      CameraTimezone::Fixed(offset) => CameraClock::fixed(offset),
+      CameraTimezone::Auto => match detect_offset(client) {
+        Ok(Some(offset)) => {
+          info!("Camera clock is at UTC{}", offset);
+          CameraClock::fixed(offset)
+        },
+        _ => {
+          warn!("Couldn't detect the timezone of the camera, assuming local time");
+          CameraClock::local()
+        }
+      },
+    }
+  }
+
+  pub fn from_config(config: &Config, client: &Client) -> Self {
+    CameraClock::resolve(config.camera_timezone, client)
+  }
+
+  pub fn to_utc(&self, date: &NaiveDateTime) -> NaiveDateTime {
+    match self.offset {
+      Some(offset) => *date - offset.local_minus_utc(),
+      None => local_to_utc(date),
+    }
+  }
+
+  /// The time the camera clock showed at `date` in UTC.
+  pub fn to_camera_time(&self, date: &NaiveDateTime) -> NaiveDateTime {
+    match self.offset {
+      Some(offset) => *date + offset.local_minus_utc(),
+      None => utc_to_local(date),
+    }
+  }
+}
+
+/// Converts a local time of this computer to UTC. Times which don't exist
+/// because of a DST change use the offset before the change.
+pub fn local_to_utc(date: &NaiveDateTime) -> NaiveDateTime {
+  match Local.from_local_datetime(date).earliest() {
+    Some(local) => local.naive_utc(),
+    None => *date - Local.offset_from_utc_datetime(date).local_minus_utc(),
+  }
+}
+
+/// Converts UTC to the local time of this computer, for display.
+pub fn utc_to_local(date: &NaiveDateTime) -> NaiveDateTime {
+  Local.from_utc_datetime(date).naive_local()
+}
+
+/// The time shown by the camera clock, taken from the `Date` header of
+/// its responses. `None` if the camera doesn't send one.
+pub fn camera_time(client: &Client) -> Result<Option<NaiveDateTime>> {
+  let res = try!(client.get(&format!("{}get_caminfo.cgi", BASE_URL)).send());
+  Ok(res.headers.get::<Date>().and_then(|date| {
+    NaiveDateTime::parse_from_str(&date.to_string(), "%a, %d %b %Y %H:%M:%S GMT").ok()
+  }))
+}
+
+/// Estimates the UTC offset of the camera clock from the time it
+/// reports.
+pub fn detect_offset(client: &Client) -> Result<Option<FixedOffset>> {
+  let camera_now = try!(camera_time(client));
+  Ok(camera_now.and_then(|camera_now| offset_between(&camera_now, &UTC::now().naive_utc())))
+}
+
+/// The timezone offset of a clock showing `camera_now` at `utc_now`.
+/// Rounded to 15 minutes, as camera clocks drift.
+fn offset_between(camera_now: &NaiveDateTime, utc_now: &NaiveDateTime) -> Option<FixedOffset> {
+  const QUARTER: i64 = 15 * 60;
+  let diff = (*camera_now - *utc_now).num_seconds();
+  let rounded = (diff + diff.signum() * QUARTER / 2) / QUARTER * QUARTER;
+
+  // Anything beyond real timezones is a wrong clock, not an offset
+  if rounded.abs() > 14 * 3600 {
+    return None;
+  }
+  FixedOffset::east_opt(rounded as i32)
+}
+
+/// Outcome of `sync_clock`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockSync {
+  /// The offset the camera clock was set to
+  pub offset: FixedOffset,
+  /// How far the camera clock was ahead (positive) or behind, if the
+  /// camera reported its time
+  pub drift: Option<Duration>,
+}
+
+/// The offset the camera clock is set to by `sync_clock`: The configured
+/// one, or the current one of this computer.
+pub fn sync_offset(timezone: CameraTimezone) -> FixedOffset {
+  match timezone {
+    CameraTimezone::Fixed(offset) => offset,
+    CameraTimezone::Local | CameraTimezone::Auto => {
+      Local.offset_from_utc_datetime(&UTC::now().naive_utc())
+    },
+  }
+}
+
+pub fn sync_clock(client: &Client, offset: FixedOffset) -> Result<ClockSync> {
+  let camera_now = try!(camera_time(client));
+  let utc_now = UTC::now().naive_utc();
+
+  try!(request(client, &format!("set_utctimediff.cgi?utctime={}&diff={}",
+                                utc_now.format("%Y%m%dT%H%M%S"), format_diff(offset))));
+
+  Ok(ClockSync {
+    offset: offset,
+    drift: camera_now.map(|camera_now| clock_drift(&camera_now, &utc_now, offset)),
+  })
+}
+
+/// Resolves `Auto` to the offset the camera clock is at right now, so it
+/// still applies to the images on the card after `sync_clock`.
+pub fn current_timezone(timezone: CameraTimezone) -> CameraTimezone {
+  match timezone {
+    CameraTimezone::Auto => match detect_offset(&Client::new()) {
+      Ok(Some(offset)) => CameraTimezone::Fixed(offset),
+      _ => CameraTimezone::Auto,
+    },
+    timezone => timezone,
+  }
+}
+
+pub fn sync_camera_clock(config: &Config) -> Result<ClockSync> {
+  sync_clock(&Client::new(), sync_offset(config.camera_timezone))
+}
+
+/// How far a clock showing `camera_now` at `utc_now` is off from the
+/// correct time at `offset`.
+fn clock_drift(camera_now: &NaiveDateTime, utc_now: &NaiveDateTime, offset: FixedOffset) -> Duration {
+  *camera_now - (*utc_now + offset.local_minus_utc())
+}
+
+/// Formats `offset` the way `set_utctimediff.cgi` expects it, e.g.
+/// `%2B0200` for +02:00, URL-encoded.
+fn format_diff(offset: FixedOffset) -> String {
+  let minutes = offset.local_minus_utc().num_minutes();
+  let sign = if minutes < 0 { "-" } else { "%2B" };
+  format!("{}{:02}{:02}", sign, minutes.abs() / 60, minutes.abs() % 60)
+}
+
+#[test]
+fn test_camera_clock() {
+  use chrono::NaiveDate;
+
+  let utc = NaiveDate::from_ymd(2016, 4, 27).and_hms(17, 39, 10);
+  let camera = NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 39, 10);
+
+  let clock = CameraClock::fixed(FixedOffset::east(2 * 3600));
+  assert_eq!(clock.to_utc(&camera), utc);
+  assert_eq!(clock.to_camera_time(&utc), camera);
+
+  let drifted = NaiveDate::from_ymd(2016, 4, 27).and_hms(19, 45, 0);
+  assert_eq!(offset_between(&drifted, &utc), Some(FixedOffset::east(2 * 3600)));
+  let behind = NaiveDate::from_ymd(2016, 4, 27).and_hms(12, 41, 0);
+  assert_eq!(offset_between(&behind, &utc), Some(FixedOffset::west(5 * 3600)));
+  let wrong = NaiveDate::from_ymd(2000, 1, 1).and_hms(0, 0, 0);
+  assert_eq!(offset_between(&wrong, &utc), None);
+
+  assert_eq!(utc_to_local(&local_to_utc(&camera)), camera);
+
+  let offset = FixedOffset::east(2 * 3600);
+  assert_eq!(clock_drift(&drifted, &utc, offset), Duration::seconds(350));
+  assert_eq!(clock_drift(&camera, &utc, offset), Duration::zero());
+  assert_eq!(format_diff(offset), "%2B0200");
+  assert_eq!(format_diff(FixedOffset::west(5 * 3600 + 30 * 60)), "-0530");
+  assert_eq!(sync_offset(CameraTimezone::Fixed(offset)), offset);
+}
@@ -1,20 +1,33 @@
 use std::{fs, io};
-use std::collections::LinkedList;
+use std::cell::Cell;
+use std::collections::{HashSet,LinkedList};
 use std::fs::File;
 use std::io::{Read,Write};
 use std::path::{Path,PathBuf};
 
 use error::{Error,Result};
+use camera::CameraInfo;
 use config::*;
+use dedup::deduplicate;
+use exif::{ExifData,read_exif};
+use gpx::Track;
+use history::{History,HistoryEntry};
+use manifest::{HashingWriter,Manifest};
+use naming::{Renamer,with_suffix};
+use pairs::apply_pair_strategy;
+use state::{DATE_FORMAT,StateFile};
+use timezone::CameraClock;
+use xmp::{ImportInfo,Sidecar};
 
-use chrono::{NaiveDate,NaiveDateTime};
+use chrono::{NaiveDate,NaiveDateTime,UTC};
+use filetime::{self,FileTime};
 use hyper::Client;
 use hyper::status::StatusCode;
 use regex::Regex;
 
-const BASE_URL: &'static str = "http://192.168.0.10/";
+pub const BASE_URL: &'static str = "http://192.168.0.10/";
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TransferItem {
   pub parent: String,
   pub filename: String,
@@ -69,43 +82,141 @@ impl TransferItem {
     format!("{}/{}", self.parent, self.filename)
   }
 
-  pub fn download<P: AsRef<Path>>(&self,
-                                  client: &Client,
-                                  target: &P,
-                                  strategy: OverwriteStrategy) -> Result<()> {
-    let url = format!("{}{}", BASE_URL, self.path());
-    debug!("Fetching {}", url);
-    let mut res = try!(client.get(&url).send());
-    assert_eq!(res.status, StatusCode::Ok);
-
-    let mut tmp = target.as_ref().to_str().unwrap().to_string();
-
-    // Implement overwrite strategy
-    if Path::new(&tmp).exists() {
-      use OverwriteStrategy::*;
+  /// Downloads this item, or a JPEG of it scaled down to `resize` pixels
+  /// wide (see `is_jpeg`). `target` gets the capture time (in UTC, from
+  /// EXIF or else the camera's file date) and picks the path, e.g. to
+  /// name the file after it. The `times` of a written file are set to
+  /// the capture time.
+  pub fn download<F>(&self,
+                     client: &Client,
+                     resize: Option<u32>,
+                     clock: &CameraClock,
+                     strategy: OverwriteStrategy,
+                     times: FileTimes,
+                     target: F) -> Result<Download>
+    where F: FnOnce(&NaiveDateTime) -> PathBuf {
+    let url = match resize {
+      None => format!("{}{}", BASE_URL, self.path()),
+      Some(size) => format!("{}get_resizeimg.cgi?DIR={}&size={}", BASE_URL, self.path(), size),
+    };
 
-      println!("Target {} already exists. {}", tmp, match strategy {
-        Skip => "Skipping",
-        Overwrite => "Replacing",
-      });
+    let mut exif = ExifData::default();
+    let mut captured = self.date;
+    let (target, hash) = try!(fetch_to(client, &url, strategy, |head| {
+      exif = read_exif(io::Cursor::new(head)).unwrap_or(ExifData::default());
+      captured = clock.to_utc(&exif.capture_time().unwrap_or(self.date));
+      target(&captured)
+    }));
 
-      match strategy {
-        Skip => return Ok(()),
-        Overwrite => (),
+    if hash.is_some() {
+      if let Err(e) = set_file_times(&target, &captured, times) {
+        warn!("Couldn't set file times of {}: {}", target.display(), e);
       }
     }
 
-    tmp.push_str(".incomplete");
+    Ok(Download {
+      target: target,
+      hash: hash,
+      exif: exif,
+      captured: captured,
+    })
+  }
 
-    {
-      let mut out = try!(File::create(&tmp));
-      try!(io::copy(&mut res, &mut out));
-      try!(out.sync_all());
-    }
+  pub fn is_raw(&self) -> bool {
+    self.filename.to_lowercase().ends_with(".orf")
+  }
 
-    try!(fs::rename(tmp, target));
+  pub fn is_jpeg(&self) -> bool {
+    let filename = self.filename.to_lowercase();
+    filename.ends_with(".jpg") || filename.ends_with(".jpeg")
+  }
+
+  pub fn download_thumbnail<P: AsRef<Path>>(&self, client: &Client, target: &P) -> Result<()> {
+    let url = format!("{}get_thumbnail.cgi?DIR={}", BASE_URL, self.path());
+    try!(fetch(client, &url, target.as_ref(), OverwriteStrategy::Overwrite));
     Ok(())
   }
+
+  pub fn download_screennail<P: AsRef<Path>>(&self, client: &Client, target: &P) -> Result<()> {
+    let url = format!("{}get_screennail.cgi?DIR={}", BASE_URL, self.path());
+    try!(fetch(client, &url, target.as_ref(), OverwriteStrategy::Overwrite));
+    Ok(())
+  }
+}
+
+/// Result of `TransferItem::download`.
+pub struct Download {
+  pub target: PathBuf,
+  /// SHA-256 of the file, `None` if an existing file was kept
+  pub hash: Option<String>,
+  pub exif: ExifData,
+  /// In UTC
+  pub captured: NaiveDateTime,
+}
+
+/// The EXIF data of JPEGs and ORFs is at the start of the file, so
+/// `fetch_to` reads this much before picking the target.
+const HEAD_SIZE: u64 = 128 * 1024;
+
+/// Downloads `url` to `target`. The file is written next to `target` and
+/// renamed once it is complete. Returns the SHA-256 of the file, or
+/// `None` if an existing file was kept.
+fn fetch(client: &Client,
+         url: &str,
+         target: &Path,
+         strategy: OverwriteStrategy) -> Result<Option<String>> {
+  fetch_to(client, url, strategy, |_| target.to_path_buf()).map(|(_, hash)| hash)
+}
+
+/// Like `fetch`, but `target` picks the path from the start of the file.
+fn fetch_to<F>(client: &Client,
+               url: &str,
+               strategy: OverwriteStrategy,
+               target: F) -> Result<(PathBuf, Option<String>)>
+  where F: FnOnce(&[u8]) -> PathBuf {
+  debug!("Fetching {}", url);
+  let mut res = try!(client.get(url).send());
+  if res.status != StatusCode::Ok {
+    return Err(Error::ProtocolError)
+  }
+
+  let mut head = Vec::new();
+  try!(res.by_ref().take(HEAD_SIZE).read_to_end(&mut head));
+  let target = target(&head);
+  if let Some(parent) = target.parent() {
+    try!(fs::create_dir_all(parent));
+  }
+
+  let mut tmp = target.to_str().unwrap().to_string();
+
+  // Implement overwrite strategy
+  if Path::new(&tmp).exists() {
+    use OverwriteStrategy::*;
+
+    println!("Target {} already exists. {}", tmp, match strategy {
+      Skip => "Skipping",
+      Overwrite => "Replacing",
+    });
+
+    match strategy {
+      Skip => return Ok((target, None)),
+      Overwrite => (),
+    }
+  }
+
+  tmp.push_str(".incomplete");
+
+  // Hash while streaming, the data is on its way to the disk anyway
+  let hash = {
+    let mut out = HashingWriter::new(try!(File::create(&tmp)));
+    try!(out.write_all(&head));
+    try!(io::copy(&mut res, &mut out));
+    try!(out.get_ref().sync_all());
+    out.hash()
+  };
+
+  try!(fs::rename(tmp, &target));
+  Ok((target, Some(hash)))
 }
 
 #[test]
@@ -121,17 +232,24 @@ fn test_from_row() {
   }
 }
 
-fn request_list(client: &Client, endpoint: &str) -> Result<Vec<TransferItem>> {
+pub fn request(client: &Client, endpoint: &str) -> Result<String> {
   let mut url = BASE_URL.to_string();
   url.push_str(endpoint);
 
-  debug!("fetching listing at {:?}", url);
+  debug!("fetching {:?}", url);
 
   let mut res = try!(client.get(&url).send());
-  assert_eq!(res.status, StatusCode::Ok);
+  if res.status != StatusCode::Ok {
+    return Err(Error::ProtocolError)
+  }
 
   let mut body = String::new();
   try!(res.read_to_string(&mut body));
+  Ok(body)
+}
+
+pub fn request_list(client: &Client, endpoint: &str) -> Result<Vec<TransferItem>> {
+  let body = try!(request(client, endpoint));
   let mut rows = body.split("\r\n");
 
   let version = rows.next().expect("Invalid camera response");
@@ -148,7 +266,7 @@ fn request_list(client: &Client, endpoint: &str) -> Result<Vec<TransferItem>> {
   Ok(rows)
 }
 
-fn list_items(client: &Client) -> Result<Vec<TransferItem>> {
+pub fn list_items(client: &Client) -> Result<Vec<TransferItem>> {
   fn list_rec(client: &Client, dir: &str) -> Result<LinkedList<TransferItem>> {
     let endpoint = format!("get_imglist.cgi?DIR={}", dir);
     let entries = try!(request_list(&client, &endpoint));
@@ -169,12 +287,44 @@ fn list_items(client: &Client) -> Result<Vec<TransferItem>> {
   Ok(entries)
 }
 
+pub fn list_camera_items() -> Result<Vec<TransferItem>> {
+  list_items(&Client::new())
+}
+
+pub fn ping_camera(client: &Client) -> Result<()> {
+  try!(request_list(&client, "get_imglist.cgi?DIR=/DCIM"));
+  Ok(())
+}
+
+/// What became of an item, see `Transfer::item_downloaded`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+  /// Downloaded in this run
+  Written,
+  /// Already there from an earlier run, see `OverwriteStrategy::Skip`
+  Kept,
+  /// The download failed, but the transfer went on
+  Failed,
+}
+
 pub trait Transfer: Sized {
   fn from_config(c: &Config) -> Option<Self>;
+  /// Identifies the kind of transfer, e.g. in XMP sidecars
+  fn name(&self) -> &'static str;
   fn download_directory(&self) -> &PathBuf;
 
+  /// Called before anything is transferred, e.g. to migrate old state.
+  fn prepare(&self) -> Result<()> { Ok(()) }
+  /// Hands over the camera clock, resolved once per run.
+  fn use_clock(&self, _clock: CameraClock) {}
+
   fn items(&self, client: &Client) -> Result<Vec<TransferItem>>;
-  fn item_downloaded(&self, _item: &TransferItem) -> Result<()> { Ok(()) }
+  fn item_downloaded(&self, _item: &TransferItem, _outcome: Outcome) -> Result<()> { Ok(()) }
+
+  /// Width to resize JPEGs to. Other files are skipped if set.
+  fn resize(&self) -> Option<u32> { None }
+  /// Whether all items come from the camera's transfer order.
+  fn picked_only(&self) -> bool { false }
 }
 
 // pub fn power_off() -> Result<()> {
@@ -189,106 +339,648 @@ pub trait Transfer: Sized {
 pub fn execute_transfer<T: Transfer>(transfer: T, config: &Config) -> Result<()> {
   let client = Client::new();
 
+  try!(transfer.prepare());
+  let clock = CameraClock::from_config(config, &client);
+  transfer.use_clock(clock);
+
   let entries = try!(transfer.items(&client));
+  let entries = try!(apply_pair_strategy(&client, entries, config.pair_strategy));
   let dir = transfer.download_directory().to_path_buf();
   try!(fs::create_dir_all(&dir));
 
+  // A broken history shouldn't keep anyone from getting their images
+  let history = match History::open(&config.history_file) {
+    Ok(history) => Some(history),
+    Err(e) => {
+      warn!("Couldn't open history {}: {}", config.history_file.display(), e);
+      None
+    }
+  };
+  let model = if history.is_some() || config.rename_template.is_some() {
+    CameraInfo::fetch(&client).ok().map(|info| info.model)
+  } else {
+    None
+  };
+
+  let camera = config.camera.clone().or(model.clone()).unwrap_or("camera".to_string());
+  let mut renamer = config.rename_template.as_ref()
+    .map(|template| Renamer::new(template, camera, &entries));
+
+  let mut manifest = try!(Manifest::load(&dir));
+
+  // Items in the camera's transfer order are marked as picked
+  let picked: HashSet<String> = if config.xmp_sidecars && !transfer.picked_only() {
+    match request_list(&client, "get_rsvimglist.cgi") {
+      Ok(order) => order.iter().map(TransferItem::path).collect(),
+      Err(e) => {
+        warn!("Couldn't get the transfer order: {}", e);
+        HashSet::new()
+      }
+    }
+  } else {
+    HashSet::new()
+  };
+
+  let track = config.geotag.as_ref().and_then(|geotag| {
+    match Track::load_dir(&geotag.gpx_dir) {
+      Ok(track) => {
+        println!("Loaded {} track points from {}", track.points().len(), geotag.gpx_dir.display());
+        Some(track)
+      },
+      Err(e) => {
+        warn!("Couldn't load tracks from {}: {}", geotag.gpx_dir.display(), e);
+        None
+      }
+    }
+  });
+
   // Used for formatting
   let pad_width = format!("{}", entries.len()).len();
 
+  let resize = transfer.resize();
+
   for (i,entry) in entries.iter().enumerate() {
-    let mut target = dir.clone();
-    target.push(&entry.filename);
-    println!("[{i:>pad$}/{len}] Downloading {filename} to {target}",
-             pad      = pad_width,
-             i        = i+1,
-             len      = entries.len(),
-             filename = entry.filename,
-             target   = target.display());
-
-    let result = entry.download(&client, &target, config.overwrite_strategy);
-    if result.is_err() {
-      warn!("Failed to download {}", entry.filename);
-      if config.error_strategy == ErrorStrategy::Abort {
-        return result;
+    if resize.is_some() && !entry.is_jpeg() {
+      println!("Skipping {}, only JPEGs can be resized", entry.filename);
+      continue;
+    }
+
+    let result = entry.download(&client, resize, &clock, config.overwrite_strategy,
+                                config.file_times, |captured| {
+      let name = match renamer {
+        Some(ref mut renamer) => renamer.name(entry, &clock.to_camera_time(captured), |name, item| {
+          is_free(&dir.join(resized_name(name, resize)), item, history.as_ref(), resize.is_some())
+        }),
+        None => entry.filename.clone(),
       };
+      let target = dir.join(resized_name(&name, resize));
+      println!("[{i:>pad$}/{len}] Downloading {filename} to {target}",
+               pad      = pad_width,
+               i        = i+1,
+               len      = entries.len(),
+               filename = entry.filename,
+               target   = target.display());
+      target
+    });
+    let Download { target, hash, exif, captured } = match result {
+      Ok(download) => download,
+      Err(e) => {
+        warn!("Failed to download {}", entry.filename);
+        if config.error_strategy == ErrorStrategy::Abort {
+          return Err(e);
+        };
+        // The item counts as done, like in the transfers before
+        try!(finish_item(&transfer, &entry, Outcome::Failed));
+        continue;
+      }
+    };
+    let name = target.strip_prefix(&dir).unwrap_or(&target).to_string_lossy().into_owned();
+
+    // The file might have been archived before, e.g. by another transfer
+    let stored = match (hash.as_ref(), history.as_ref()) {
+      (Some(hash), Some(history)) => {
+        deduplicate(history, hash, &target, config.duplicate_strategy)
+          .unwrap_or_else(|e| {
+            warn!("Duplicate detection for {} failed: {}", entry.filename, e);
+            target.clone()
+          })
+      },
+      _ => target.clone(),
     };
 
-    try!(transfer.item_downloaded(&entry))
+    match hash {
+      Some(ref hash) if stored == target => try!(manifest.add(&name, hash)),
+      _ => (),
+    }
+
+    let downloaded_at = UTC::now().naive_utc();
+
+    // Kept and linked files get a sidecar too, unless they have one
+    let mut sidecar = Sidecar::default();
+    if config.xmp_sidecars {
+      sidecar.import = Some(ImportInfo {
+        filename: entry.filename.clone(),
+        folder: entry.parent.clone(),
+        date: hash.as_ref().map(|_| downloaded_at),
+        picked: transfer.picked_only() || picked.contains(&entry.path()),
+        transfer: transfer.name().to_string(),
+      });
+      sidecar.keywords = config.xmp_keywords.clone();
+    }
+    if let (Some(geotag), Some(track)) = (config.geotag.as_ref(), track.as_ref()) {
+      sidecar.position = track.locate(&(captured + geotag.time_offset), geotag.max_gap);
+      if sidecar.position.is_none() {
+        println!("No position for {} in the tracks", entry.filename);
+      }
+    }
+    if !sidecar.is_empty() {
+      match sidecar.write(&stored) {
+        Ok(Some(_)) => (),
+        Ok(None) if hash.is_some() => {
+          println!("Keeping existing sidecar of {}", stored.display())
+        },
+        Ok(None) => debug!("Keeping existing sidecar of {}", stored.display()),
+        Err(e) => warn!("Couldn't write sidecar for {}: {}", stored.display(), e),
+      }
+    }
+
+    // Files kept from earlier runs are in the history already, resized
+    // copies don't belong there
+    match (history.as_ref(), hash.as_ref()) {
+      (Some(history), Some(_)) if resize.is_none() => {
+        let result = fs::metadata(&stored).map_err(Error::from).and_then(|m| {
+          history.record(&HistoryEntry {
+            camera_path: entry.path(),
+            target: stored.clone(),
+            file_size: m.len(),
+            camera_date: clock.to_utc(&entry.date),
+            capture_date: exif.capture_time().map(|date| clock.to_utc(&date)),
+            downloaded_at: downloaded_at,
+            checksum: hash.clone(),
+            camera: config.camera.clone(),
+            model: model.clone().or(exif.model.clone()),
+            serial: exif.serial.clone(),
+          })
+        });
+        if let Err(e) = result {
+          warn!("Couldn't record {} in history: {}", entry.filename, e);
+        }
+      },
+      _ => (),
+    }
+
+    let outcome = if hash.is_some() { Outcome::Written } else { Outcome::Kept };
+    try!(finish_item(&transfer, &entry, outcome))
   }
 
   Ok(())
 }
 
+/// Reports `item` to the transfer. Resized copies don't replace the
+/// originals, so they don't count as transferred, whatever the outcome.
+fn finish_item<T: Transfer>(transfer: &T, item: &TransferItem, outcome: Outcome) -> Result<()> {
+  if transfer.resize().is_some() {
+    return Ok(());
+  }
+  transfer.item_downloaded(item, outcome)
+}
+
+/// Resized downloads get the width appended, e.g. `P4270171_1600.JPG`,
+/// so they're never mistaken for originals.
+fn resized_name(name: &str, resize: Option<u32>) -> String {
+  match resize {
+    Some(size) => with_suffix(name, size as usize),
+    None => name.to_string(),
+  }
+}
+
+/// Sets the `times` of the file at `path` to `date`, which is in UTC.
+pub fn set_file_times(path: &Path, date: &NaiveDateTime, times: FileTimes) -> io::Result<()> {
+  let seconds = date.timestamp();
+  if times == FileTimes::Download || seconds < 0 {
+    return Ok(());
+  }
+
+  let mtime = FileTime::from_seconds_since_1970(seconds as u64, date.timestamp_subsec_nanos());
+  let atime = match times {
+    FileTimes::All => mtime,
+    _ => FileTime::from_last_access_time(&try!(fs::metadata(path))),
+  };
+  filetime::set_file_times(path, atime, mtime)
+}
+
+#[test]
+fn test_set_file_times() {
+  use std::os::unix::fs::MetadataExt;
+
+  let dir = ::test_util::TestDir::new("file-times");
+  let path = dir.join("P4270171.JPG");
+  File::create(&path).unwrap().write_all(b"image").unwrap();
+  let date = NaiveDate::from_ymd(2016, 4, 27).and_hms_milli(17, 39, 10, 50);
+
+  set_file_times(&path, &date, FileTimes::Download).unwrap();
+  assert!(fs::metadata(&path).unwrap().mtime() > date.timestamp());
+
+  set_file_times(&path, &date, FileTimes::Modified).unwrap();
+  let meta = fs::metadata(&path).unwrap();
+  assert_eq!((meta.mtime(), meta.mtime_nsec()), (1461778750, 50_000_000));
+  assert!(meta.atime() > date.timestamp());
+
+  set_file_times(&path, &date, FileTimes::All).unwrap();
+  assert_eq!(fs::metadata(&path).unwrap().atime(), 1461778750);
+}
+
+/// Whether `target` can take `item`: It doesn't exist yet or holds an
+/// earlier download of the same file, as far as we can tell.
+fn is_free(target: &Path, item: &TransferItem, history: Option<&History>, resized: bool) -> bool {
+  if !target.exists() {
+    return true;
+  }
+
+  match history.and_then(|h| h.source_of(target).ok()).and_then(|source| source) {
+    Some(source) => source == item.path(),
+    None => match fs::metadata(target) {
+      Ok(m) => resized || m.len() == item.file_size,
+      Err(_) => false,
+    },
+  }
+}
+
 pub struct OrderTransfer {
-  download_dir: PathBuf
+  download_dir: PathBuf,
+  record_file: PathBuf,
+  resize: Option<u32>,
+}
+
+impl OrderTransfer {
+  pub fn record_file(&self) -> &PathBuf {
+    &self.record_file
+  }
+
+  fn delivered(&self) -> Result<HashSet<String>> {
+    use std::io::{BufRead,BufReader,ErrorKind};
+    match File::open(&self.record_file) {
+      Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+      Err(e) => Err(Error::Io(e)),
+      Ok(f) => {
+        let mut delivered = HashSet::new();
+        for line in BufReader::new(f).lines() {
+          delivered.insert(try!(line));
+        }
+        Ok(delivered)
+      }
+    }
+  }
+
+  pub fn pending(&self, entries: Vec<TransferItem>) -> Result<Vec<TransferItem>> {
+    let delivered = try!(self.delivered());
+    Ok(entries.into_iter()
+       .filter(|e| !delivered.contains(&record_key(e)))
+       .collect())
+  }
+
+  fn record_delivery(&self, item: &TransferItem) -> io::Result<()> {
+    if let Some(dir) = self.record_file.parent() {
+      try!(fs::create_dir_all(dir));
+    }
+    let mut f = try!(fs::OpenOptions::new()
+                     .create(true)
+                     .append(true)
+                     .open(&self.record_file));
+    try!(writeln!(f, "{}", record_key(item)));
+    try!(f.sync_all());
+    Ok(())
+  }
+}
+
+/// Identifies an item across runs. Includes size and date so a new image
+/// reusing a filename (e.g. after formatting the card) isn't skipped.
+fn record_key(item: &TransferItem) -> String {
+  format!("{}\t{}\t{}", item.path(), item.file_size, item.date.format(DATE_FORMAT))
+}
+
+#[test]
+fn test_resized_name() {
+  assert_eq!(resized_name("P4270171.JPG", Some(1600)), "P4270171_1600.JPG");
+  assert_eq!(resized_name("P4270171.JPG", None), "P4270171.JPG");
+}
+
+#[test]
+fn test_record_key() {
+  let item = TransferItem::from_row("/DCIM/100OLYMP,P4270171.ORF,14845727,0,18587,40165");
+  assert_eq!(record_key(&item), "/DCIM/100OLYMP/P4270171.ORF\t14845727\t2016-04-27T19:39:10");
 }
 
 impl Transfer for OrderTransfer {
   fn from_config(c: &Config) -> Option<Self> {
     c.transfer_order_dir.as_ref().map(|d| OrderTransfer {
-      download_dir: d.clone()
+      download_dir: d.clone(),
+      record_file: c.transfer_order_state_file.clone(),
+      resize: c.transfer_order_resize,
     })
   }
 
+  fn name(&self) -> &'static str {
+    "transfer_order"
+  }
+
   fn download_directory(&self) -> &PathBuf {
     &self.download_dir
   }
 
+  fn resize(&self) -> Option<u32> {
+    self.resize
+  }
+
+  fn picked_only(&self) -> bool {
+    true
+  }
+
   fn items(&self, client: &Client) -> Result<Vec<TransferItem>> {
     println!("Checking for transfer order items...");
     let entries = try!(request_list(&client, "get_rsvimglist.cgi"));
     println!("Got {} items in transfer order", entries.len());
+
+    let entries = try!(self.pending(entries));
+    println!("{} of them weren't downloaded before", entries.len());
+
     Ok(entries)
   }
+
+  fn item_downloaded(&self, item: &TransferItem, outcome: Outcome) -> Result<()> {
+    // Kept files count too, they were delivered before
+    if outcome != Outcome::Failed {
+      try!(self.record_delivery(item));
+    }
+    Ok(())
+  }
 }
 
-pub struct IncrementalTransfer {
+/// Transfers an explicitly chosen set of items, e.g. from the picker.
+pub struct SelectionTransfer {
   download_dir: PathBuf,
-  state_file: PathBuf,
+  items: Vec<TransferItem>,
+}
+
+impl SelectionTransfer {
+  pub fn with_items(self, items: Vec<TransferItem>) -> Self {
+    SelectionTransfer {
+      download_dir: self.download_dir,
+      items: items,
+    }
+  }
+}
+
+impl Transfer for SelectionTransfer {
+  fn from_config(c: &Config) -> Option<Self> {
+    c.selection_dir.as_ref().map(|d| SelectionTransfer {
+      download_dir: d.clone(),
+      items: Vec::new(),
+    })
+  }
+
+  fn name(&self) -> &'static str {
+    "selection"
+  }
+
+  fn download_directory(&self) -> &PathBuf {
+    &self.download_dir
+  }
+
+  fn items(&self, _client: &Client) -> Result<Vec<TransferItem>> {
+    println!("Got {} selected items", self.items.len());
+    Ok(self.items.clone())
+  }
+}
+
+/// Transfers items given by their camera path, e.g.
+/// `/DCIM/100OLYMP/P4270171.ORF`.
+pub struct ListTransfer {
+  download_dir: PathBuf,
+  paths: Vec<String>,
+}
+
+impl ListTransfer {
+  pub fn with_paths(self, paths: Vec<String>) -> Self {
+    ListTransfer {
+      download_dir: self.download_dir,
+      paths: dedup_paths(paths.iter().map(|p| normalize_path(p))),
+    }
+  }
+}
+
+/// Drops repeated paths, keeping the first occurrence.
+fn dedup_paths<I: Iterator<Item=String>>(paths: I) -> Vec<String> {
+  let mut seen = HashSet::new();
+  paths.filter(|p| seen.insert(p.clone())).collect()
+}
+
+/// Reads camera paths, one per line. Empty lines and lines starting with
+/// `#` are ignored.
+pub fn read_path_list<R: io::BufRead>(reader: R) -> io::Result<Vec<String>> {
+  let mut paths = Vec::new();
+  for line in reader.lines() {
+    let line = try!(line);
+    let line = line.trim();
+    if !line.is_empty() && !line.starts_with("#") {
+      paths.push(line.to_string());
+    }
+  }
+  Ok(paths)
+}
+
+/// Camera paths are absolute and upper case.
+fn normalize_path(path: &str) -> String {
+  let path = path.trim().to_uppercase();
+  if path.starts_with("/") {
+    path
+  } else {
+    format!("/{}", path)
+  }
+}
+
+#[test]
+fn test_read_path_list() {
+  let list = "/DCIM/100OLYMP/P4270171.ORF\n\n# comment\n  DCIM/100OLYMP/p4270172.jpg \n";
+  let paths = read_path_list(io::Cursor::new(list)).unwrap();
+  assert_eq!(paths, vec!["/DCIM/100OLYMP/P4270171.ORF", "DCIM/100OLYMP/p4270172.jpg"]);
+  assert_eq!(normalize_path(&paths[1]), "/DCIM/100OLYMP/P4270172.JPG");
+
+  let paths = vec!["/DCIM/100OLYMP/P4270171.ORF", "dcim/100olymp/p4270171.orf", "/DCIM/100OLYMP/P4270172.JPG"];
+  assert_eq!(ListTransfer { download_dir: PathBuf::new(), paths: Vec::new() }
+               .with_paths(paths.iter().map(|p| p.to_string()).collect()).paths,
+             vec!["/DCIM/100OLYMP/P4270171.ORF", "/DCIM/100OLYMP/P4270172.JPG"]);
+}
+
+impl Transfer for ListTransfer {
+  fn from_config(c: &Config) -> Option<Self> {
+    c.selection_dir.as_ref().map(|d| ListTransfer {
+      download_dir: d.clone(),
+      paths: Vec::new(),
+    })
+  }
+
+  fn name(&self) -> &'static str {
+    "list"
+  }
+
+  fn download_directory(&self) -> &PathBuf {
+    &self.download_dir
+  }
+
+  fn items(&self, client: &Client) -> Result<Vec<TransferItem>> {
+    println!("Resolving {} paths...", self.paths.len());
+
+    // Only list the folders we need
+    let mut parents: Vec<&str> = self.paths.iter()
+      .filter_map(|p| p.rfind('/').map(|i| &p[..i]))
+      .collect();
+    parents.sort();
+    parents.dedup();
+
+    let mut listing = Vec::new();
+    for parent in parents {
+      let endpoint = format!("get_imglist.cgi?DIR={}", parent);
+      // Paths in folders we can't list are reported as not found below
+      match request_list(&client, &endpoint) {
+        Ok(items) => listing.extend(items),
+        Err(e) => warn!("Couldn't list {}: {}", parent, e),
+      }
+    }
+
+    let mut entries = Vec::new();
+    for path in self.paths.iter() {
+      match listing.iter().find(|item| item.path().to_uppercase() == *path) {
+        Some(item) => entries.push(item.clone()),
+        None => println!("{} not found on camera", path),
+      }
+    }
+
+    println!("Got {} items to download", entries.len());
+    Ok(entries)
+  }
 }
 
-const DATE_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%S";
+pub struct IncrementalTransfer {
+  download_dir: PathBuf,
+  state: StateFile,
+  /// Where older versions kept the state
+  legacy_state_file: PathBuf,
+  resize: Option<u32>,
+  /// Knows where renamed files went
+  history_file: PathBuf,
+  timezone: CameraTimezone,
+  clock: Cell<Option<CameraClock>>,
+}
 
 impl IncrementalTransfer {
-  fn last_download_date(&self) -> Option<NaiveDateTime> {
-    use std::io::ErrorKind;
-    match File::open(&self.state_file) {
-      Err(ref e) if e.kind() == ErrorKind::NotFound => None,
-      Err(ref e) => panic!(e.to_string()),
-      Ok(mut f) => {
-        let mut buf = String::new();
-        f.read_to_string(&mut buf).expect("Failed to read from state file");
-        let date = NaiveDateTime::parse_from_str(&buf.trim(), DATE_FORMAT)
-          .expect("Corrup history file");
-        debug!("read date from state file: {}", date);
-        Some(date)
+  pub fn state_file(&self) -> &PathBuf {
+    self.state.path()
+  }
+
+  /// The camera clock, see `use_clock`. Otherwise detected on first use
+  /// with `camera_timezone = "auto"`, so the camera has to be reachable.
+  pub fn clock(&self) -> CameraClock {
+    if let Some(clock) = self.clock.get() {
+      return clock;
+    }
+
+    let clock = CameraClock::resolve(self.timezone, &Client::new());
+    self.clock.set(Some(clock));
+    clock
+  }
+
+  pub fn is_new(&self, item: &TransferItem, last_downloaded: Option<NaiveDateTime>) -> bool {
+    last_downloaded.map_or(true, |last| self.clock().to_utc(&item.date) > last)
+  }
+
+  /// Returns the date in UTC, or `None` if no state file exists yet.
+  pub fn read_state(&self) -> Result<Option<NaiveDateTime>> {
+    self.state.read()
+  }
+
+  /// The newest item of `listing` found in the download directory with
+  /// the same size (renamed ones via the history) counts as the last
+  /// one downloaded. `verify_exif` also compares the capture date.
+  pub fn rebuild_state(&self,
+                       listing: &[TransferItem],
+                       verify_exif: bool) -> Result<Option<NaiveDateTime>> {
+    let history = History::open(&self.history_file).ok();
+
+    let newest = listing.iter()
+      .filter(|item| {
+        let mut candidates = vec![self.download_dir.join(&item.filename)];
+        if let Some(ref history) = history {
+          candidates.extend(history.targets_of(&item.path()).unwrap_or(Vec::new())
+            .into_iter()
+            .filter(|target| target.starts_with(&self.download_dir)));
+        }
+
+        candidates.iter().any(|target| {
+          let exists = match fs::metadata(target) {
+            Ok(m) => m.len() == item.file_size,
+            Err(_) => false,
+          };
+          exists && (!verify_exif || exif_date_matches(target, item))
+        })
+      })
+      .map(|item| self.clock().to_utc(&item.date))
+      .max();
+
+    if let Some(date) = newest {
+      try!(self.state.write(&date));
+    }
+    Ok(newest)
+  }
+
+  fn last_download_date(&self, listing: &[TransferItem]) -> Result<Option<NaiveDateTime>> {
+    match self.state.read_or_recover() {
+      Ok(date) => Ok(date),
+      Err(e) => {
+        println!("{}, rebuilding it from {}", e, self.download_dir.display());
+        self.rebuild_state(listing, false)
       }
     }
   }
 
   fn store_download_date(&self, date: &NaiveDateTime) -> io::Result<()> {
-    // TODO: Make sure we don't write an older date (can happen for camera-developed ORFs)
-    let mut f = try!(File::create(&self.state_file));
-    try!(f.write_fmt(format_args!("{}", date.format(DATE_FORMAT))));
-    try!(f.sync_all());
-    Ok(())
+    // Siblings added for RAW+JPEG pairs or camera-developed files can be
+    // older than what we already stored
+    if let Ok(Some(last)) = self.state.read() {
+      if last >= *date {
+        return Ok(());
+      }
+    }
+
+    self.state.write(date)
+  }
+}
+
+/// Whether the EXIF capture date of `file` is the date of `item`. FAT
+/// timestamps have a resolution of two seconds.
+fn exif_date_matches(file: &Path, item: &TransferItem) -> bool {
+  match ExifData::read(file) {
+    Ok(ExifData { date_time_original: Some(date), .. }) => {
+      (date - item.date).num_seconds().abs() <= 2
+    },
+    _ => false,
   }
 }
 
 impl Transfer for IncrementalTransfer {
   fn from_config(c: &Config) -> Option<Self> {
     c.download_dir.as_ref().map(|dir| {
-      let mut state_file = dir.clone();
-      state_file.push("omd-downloader.state");
-
       IncrementalTransfer {
         download_dir: dir.clone(),
-        state_file: state_file,
+        state: StateFile::new(c.incremental_state_file.clone()),
+        legacy_state_file: dir.join("omd-downloader.state"),
+        resize: c.incremental_resize,
+        history_file: c.history_file.clone(),
+        timezone: c.camera_timezone,
+        clock: Cell::new(None),
       }
     })
   }
 
+  fn name(&self) -> &'static str {
+    "incremental"
+  }
+
+  fn use_clock(&self, clock: CameraClock) {
+    self.clock.set(Some(clock));
+  }
+
+  fn prepare(&self) -> Result<()> {
+    let legacy = &self.legacy_state_file;
+    match self.state.migrate_from(legacy) {
+      Ok(true) => println!("Moved state file {} to {}",
+                           legacy.display(), self.state.path().display()),
+      Ok(false) => (),
+      Err(e) => warn!("Couldn't move state file {}: {}", legacy.display(), e),
+    }
+    Ok(())
+  }
+
   fn download_directory(&self) -> &PathBuf {
     &self.download_dir
   }
@@ -296,23 +988,47 @@ impl Transfer for IncrementalTransfer {
   fn items(&self, client: &Client) -> Result<Vec<TransferItem>> {
     println!("Checking for new files...");
 
-    let last_downloaded = self.last_download_date();
     let entries = try!(list_items(&client));
+    let last_downloaded = try!(self.last_download_date(&entries));
 
-    let entries: Vec<_> = match last_downloaded {
-      None => entries.into_iter().collect(),
-      Some(date) => entries.into_iter()
-        .filter(|e| e.date > date)
-        .collect()
-    };
+    let entries: Vec<_> = entries.into_iter()
+      .filter(|e| self.is_new(e, last_downloaded))
+      .collect();
 
     println!("Got {} new files to download", entries.len());
     Ok(entries)
   }
 
-  fn item_downloaded(&self, item: &TransferItem) -> Result<()> {
-    try!(self.store_download_date(&item.date));
+  fn item_downloaded(&self, item: &TransferItem, _outcome: Outcome) -> Result<()> {
+    try!(self.store_download_date(&self.clock().to_utc(&item.date)));
 
     Ok(())
   }
+
+  fn resize(&self) -> Option<u32> {
+    self.resize
+  }
+}
+
+#[test]
+fn test_finish_resized_item() {
+  let dir = ::test_util::TestDir::new("finish-item");
+  let mut transfer = IncrementalTransfer {
+    download_dir: dir.to_path_buf(),
+    state: StateFile::new(dir.join("em5.state")),
+    legacy_state_file: dir.join("omd-downloader.state"),
+    resize: Some(1024),
+    history_file: dir.join("history.sqlite"),
+    timezone: CameraTimezone::Local,
+    clock: Cell::new(Some(CameraClock::local())),
+  };
+  let item = TransferItem::from_row("/DCIM/100OLYMP,P4270171.JPG,4845727,0,18587,40165");
+
+  // A failed resized copy mustn't skip the original in later runs
+  finish_item(&transfer, &item, Outcome::Failed).unwrap();
+  assert_eq!(transfer.read_state().unwrap(), None);
+
+  transfer.resize = None;
+  finish_item(&transfer, &item, Outcome::Failed).unwrap();
+  assert!(transfer.read_state().unwrap().is_some());
 }
@@ -63,6 +63,47 @@ impl<'a> WifiInterface<'a> {
     None
   }
 
+  fn names(conn: &Connection) -> Vec<String> {
+    let p = Props::new(&conn,
+                       "fi.w1.wpa_supplicant1",
+                       "/fi/w1/wpa_supplicant1",
+                       "fi.w1.wpa_supplicant1",
+                       DBUS_TIMEOUT);
+
+    let mut names = Vec::new();
+    if let Ok(MessageItem::Array(interfaces)) = p.get("Interfaces") {
+      for path in interfaces.iter() {
+        if let MessageItem::ObjectPath(ref path) = *path {
+          let ip = Props::new(&conn,
+                              "fi.w1.wpa_supplicant1",
+                              path.clone(),
+                              "fi.w1.wpa_supplicant1.Interface",
+                              DBUS_TIMEOUT);
+
+          if let Ok(MessageItem::Str(ifname)) = ip.get("Ifname") {
+            names.push(ifname);
+          }
+        }
+      }
+    }
+
+    names
+  }
+
+  fn networks(&'a self) -> Vec<WifiNetwork<'a>> {
+    let mut result = Vec::new();
+
+    if let Ok(MessageItem::Array(networks)) = self.props.get("Networks") {
+      for network in networks.iter() {
+        if let MessageItem::ObjectPath(ref network) = *network {
+          result.push(WifiNetwork::new(network.clone(), &self));
+        }
+      }
+    }
+
+    result
+  }
+
   fn current_network(&'a self) -> Option<WifiNetwork<'a>> {
     if let Ok(MessageItem::ObjectPath(network)) = self.props.get("CurrentNetwork") {
       Some(WifiNetwork::new(network, self))
@@ -106,6 +147,34 @@ if let Ok(Array(networks)) = props.get("Networks") {
 None
   }
 
+  fn visible_ssids(&self) -> Vec<String> {
+    let mut ssids = Vec::new();
+
+    if let Ok(MessageItem::Array(bsss)) = self.props.get("BSSs") {
+      for bss in bsss.iter() {
+        if let MessageItem::ObjectPath(ref bss) = *bss {
+          let p = Props::new(self.conn,
+                             "fi.w1.wpa_supplicant1",
+                             bss.clone(),
+                             "fi.w1.wpa_supplicant1.BSS",
+                             DBUS_TIMEOUT);
+
+          if let Ok(MessageItem::Array(bytes)) = p.get("SSID") {
+            let bytes: Vec<u8> = bytes.iter()
+              .filter_map(|b| match *b {
+                MessageItem::Byte(b) => Some(b),
+                _ => None,
+              })
+              .collect();
+            ssids.push(String::from_utf8_lossy(&bytes).into_owned());
+          }
+        }
+      }
+    }
+
+    ssids
+  }
+
   pub fn is_up(&self) -> bool {
     use get_if_addrs;
     get_if_addrs::get_if_addrs().unwrap().iter()
@@ -193,6 +262,54 @@ impl<'a> WifiNetwork<'a> {
   }
 }
 
+/// Verifies that `config.interface` is managed by wpa_supplicant and
+/// that a network for `config.ssid` is configured on it.
+pub fn check_network(config: &WifiConfig) -> result::Result<(), String> {
+  let c = try!(Connection::get_private(BusType::System)
+               .map_err(|e| format!("Couldn't connect to system bus: {:?}", e)));
+
+  let interface = try!(WifiInterface::find(&c, &config.interface)
+                       .ok_or(format!("Couldn't find interface {}", config.interface)));
+
+  match interface.find_network(&config.ssid) {
+    Some(_) => Ok(()),
+    None => Err(format!("Couldn't find camera network {}", config.ssid)),
+  }
+}
+
+/// Returns the SSIDs of all networks wpa_supplicant currently sees on
+/// `interface`.
+pub fn visible_ssids(interface: &str) -> result::Result<Vec<String>, String> {
+  let c = try!(Connection::get_private(BusType::System)
+               .map_err(|e| format!("Couldn't connect to system bus: {:?}", e)));
+
+  let interface = try!(WifiInterface::find(&c, interface)
+                       .ok_or(format!("Couldn't find interface {}", interface)));
+
+  Ok(interface.visible_ssids())
+}
+
+/// Names of all interfaces managed by wpa_supplicant.
+pub fn interfaces() -> result::Result<Vec<String>, String> {
+  let c = try!(Connection::get_private(BusType::System)
+               .map_err(|e| format!("Couldn't connect to system bus: {:?}", e)));
+
+  Ok(WifiInterface::names(&c))
+}
+
+/// Returns the SSIDs of all networks configured in wpa_supplicant for
+/// `interface`.
+pub fn configured_ssids(interface: &str) -> result::Result<Vec<String>, String> {
+  let c = try!(Connection::get_private(BusType::System)
+               .map_err(|e| format!("Couldn't connect to system bus: {:?}", e)));
+
+  let interface = try!(WifiInterface::find(&c, interface)
+                       .ok_or(format!("Couldn't find interface {}", interface)));
+
+  let ssids = interface.networks().iter().map(WifiNetwork::ssid).collect();
+  Ok(ssids)
+}
+
 // TODO: Nice error handling
 use std::panic;
 pub fn with_temporary_network<F>(config: &WifiConfig, f: F) -> ()
@@ -0,0 +1,193 @@
+use std::io;
+use std::path::{Path,PathBuf};
+
+use chrono::NaiveDateTime;
+
+use gpx::Position;
+use state::write_atomically;
+
+/// The sidecar of `file`, e.g. `P4270171.ORF.xmp`. Keeping the extension
+/// tells apart the sidecars of RAW+JPEG pairs.
+pub fn sidecar_path(file: &Path) -> PathBuf {
+  let mut path = file.as_os_str().to_owned();
+  path.push(".xmp");
+  PathBuf::from(path)
+}
+
+/// Namespace of our own properties. Only this tool reads them.
+const NS_OMD: &'static str = "urn:omd-transfer:xmp:1.0";
+
+/// Where a file came from, see `Sidecar::import`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportInfo {
+  /// The file name on the card, e.g. `P4270171.ORF`
+  pub filename: String,
+  /// The folder on the card, e.g. `/DCIM/100OLYMP`
+  pub folder: String,
+  /// When the file was downloaded in UTC, `None` if it was kept from an
+  /// earlier run
+  pub date: Option<NaiveDateTime>,
+  /// Whether the image was in the camera's transfer order, written as
+  /// a rating of one star
+  pub picked: bool,
+  /// The kind of transfer, see `Transfer::name`
+  pub transfer: String,
+}
+
+/// Metadata written to an XMP sidecar. Downloaded files themselves are
+/// never modified, so their checksums stay valid.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sidecar {
+  pub position: Option<Position>,
+  pub import: Option<ImportInfo>,
+  pub keywords: Vec<String>,
+}
+
+impl Sidecar {
+  pub fn is_empty(&self) -> bool {
+    self.position.is_none() && self.import.is_none() && self.keywords.is_empty()
+  }
+
+  /// Simple properties as `(name, value)`.
+  fn properties(&self) -> Vec<(&'static str, String)> {
+    let mut properties = Vec::new();
+
+    if let Some(ref position) = self.position {
+      properties.push(("exif:GPSVersionID", "2.2.0.0".to_string()));
+      properties.push(("exif:GPSLatitude", format_coordinate(position.latitude, 'N', 'S')));
+      properties.push(("exif:GPSLongitude", format_coordinate(position.longitude, 'E', 'W')));
+      if let Some(elevation) = position.elevation {
+        let below = if elevation < 0.0 { "1" } else { "0" };
+        properties.push(("exif:GPSAltitudeRef", below.to_string()));
+        properties.push(("exif:GPSAltitude",
+                         format!("{}/10", (elevation.abs() * 10.0).round() as u64)));
+      }
+    }
+
+    if let Some(ref import) = self.import {
+      properties.push(("xmpMM:PreservedFileName", import.filename.clone()));
+      properties.push(("omd:CameraFolder", import.folder.clone()));
+      if let Some(date) = import.date {
+        properties.push(("omd:ImportDate", format!("{}Z", date.format("%Y-%m-%dT%H:%M:%S"))));
+      }
+      // One star, the rating Darktable shows for picks
+      if import.picked {
+        properties.push(("xmp:Rating", "1".to_string()));
+      }
+      properties.push(("omd:Transfer", import.transfer.clone()));
+    }
+
+    properties
+  }
+
+  pub fn to_xml(&self) -> String {
+    let mut xml = String::from(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+       <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n \
+       <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n  \
+       <rdf:Description rdf:about=\"\"\n    \
+       xmlns:exif=\"http://ns.adobe.com/exif/1.0/\"\n    \
+       xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n    \
+       xmlns:xmpMM=\"http://ns.adobe.com/xap/1.0/mm/\"\n    \
+       xmlns:dc=\"http://purl.org/dc/elements/1.1/\"");
+    xml.push_str(&format!("\n    xmlns:omd=\"{}\"", NS_OMD));
+    for (name, value) in self.properties() {
+      xml.push_str(&format!("\n    {}=\"{}\"", name, escape(&value)));
+    }
+
+    if self.keywords.is_empty() {
+      xml.push_str("/>\n");
+    } else {
+      xml.push_str(">\n   <dc:subject>\n    <rdf:Bag>\n");
+      for keyword in &self.keywords {
+        xml.push_str(&format!("     <rdf:li>{}</rdf:li>\n", escape(keyword)));
+      }
+      xml.push_str("    </rdf:Bag>\n   </dc:subject>\n  </rdf:Description>\n");
+    }
+    xml.push_str(" </rdf:RDF>\n</x:xmpmeta>\n");
+    xml
+  }
+
+  /// Writes the sidecar of `file`. An existing one is kept, it might hold
+  /// edits, e.g. from Darktable. Returns the path if written.
+  pub fn write(&self, file: &Path) -> io::Result<Option<PathBuf>> {
+    let path = sidecar_path(file);
+    if path.exists() {
+      return Ok(None);
+    }
+    try!(write_atomically(&path, self.to_xml().as_bytes()));
+    Ok(Some(path))
+  }
+}
+
+/// Formats a coordinate as XMP wants it: degrees, minutes with
+/// fraction and direction, e.g. `52,30.000000N`.
+fn format_coordinate(value: f64, positive: char, negative: char) -> String {
+  let direction = if value < 0.0 { negative } else { positive };
+  // In 1/1000000 minutes, rounded first so 59.9999999 carries into the
+  // degrees
+  let total = (value.abs() * 60_000_000.0).round() as u64;
+  let (degrees, minutes) = (total / 60_000_000, total % 60_000_000);
+  format!("{},{}.{:06}{}", degrees, minutes / 1_000_000, minutes % 1_000_000, direction)
+}
+
+fn escape(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+#[test]
+fn test_sidecar() {
+  assert_eq!(sidecar_path(Path::new("/tmp/P4270171.ORF")), PathBuf::from("/tmp/P4270171.ORF.xmp"));
+  assert!(Sidecar::default().is_empty());
+
+  assert_eq!(format_coordinate(52.5005, 'N', 'S'), "52,30.030000N");
+  assert_eq!(format_coordinate(-0.5, 'E', 'W'), "0,30.000000W");
+  assert_eq!(format_coordinate(12.9999999999, 'N', 'S'), "13,0.000000N");
+
+  let sidecar = Sidecar {
+    position: Some(Position {
+      latitude: 52.5,
+      longitude: -13.25,
+      elevation: Some(-2.56),
+    }),
+    ..Sidecar::default()
+  };
+  let xml = sidecar.to_xml();
+  assert!(xml.contains("exif:GPSLatitude=\"52,30.000000N\""));
+  assert!(xml.contains("exif:GPSLongitude=\"13,15.000000W\""));
+  assert!(xml.contains("exif:GPSAltitudeRef=\"1\""));
+  assert!(xml.contains("exif:GPSAltitude=\"26/10\""));
+  assert!(xml.contains("\"/>\n </rdf:RDF>"));
+  assert_eq!(escape("a<\"&\">"), "a&lt;&quot;&amp;&quot;&gt;");
+
+  let sidecar = Sidecar {
+    import: Some(ImportInfo {
+      filename: "P4270171.ORF".to_string(),
+      folder: "/DCIM/100OLYMP".to_string(),
+      date: NaiveDateTime::parse_from_str("2016-04-28T08:00:00", "%Y-%m-%dT%H:%M:%S").ok(),
+      picked: true,
+      transfer: "transfer_order".to_string(),
+    }),
+    keywords: vec!["E-M10".to_string(), "R&D".to_string()],
+    ..Sidecar::default()
+  };
+  let xml = sidecar.to_xml();
+  assert!(xml.contains("xmpMM:PreservedFileName=\"P4270171.ORF\""));
+  assert!(xml.contains("omd:CameraFolder=\"/DCIM/100OLYMP\""));
+  assert!(xml.contains("omd:ImportDate=\"2016-04-28T08:00:00Z\""));
+  assert!(xml.contains("xmp:Rating=\"1\""));
+  assert!(xml.contains("<rdf:li>E-M10</rdf:li>\n     <rdf:li>R&amp;D</rdf:li>"));
+  assert!(!xml.contains("GPSLatitude"));
+
+  let mut kept = sidecar.clone();
+  kept.import.as_mut().unwrap().date = None;
+  assert!(!kept.to_xml().contains("ImportDate"));
+
+  let dir = ::test_util::TestDir::new("sidecar");
+  let file = dir.join("P4270171.ORF");
+  assert_eq!(sidecar.write(&file).unwrap(), Some(sidecar_path(&file)));
+  assert_eq!(Sidecar::default().write(&file).unwrap(), None);
+}